@@ -1,35 +1,58 @@
-pub async fn open_browser_and_authenticate_twitch(client_id: String, port: u16) {
-    if tokio::fs::metadata("twitch_token.json").await.is_ok() {
-        return;
-    }
+use rand::{distributions::Alphanumeric, Rng};
+
+use crate::{spotify::client::SPOTIFY_ACCOUNTS_BASE_URL, twitch_auth::TWITCH_ID_BASE_URL};
 
-    let scopes = vec!["chat:edit", "chat:read"].join("+").replace(":", "%3A");
+/// Random value for the OAuth `state` parameter, checked again when the callback arrives
+pub fn generate_oauth_state() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+pub async fn open_browser_and_authenticate_twitch(
+    client_id: String,
+    port: u16,
+    oauth_state: &str,
+    scopes: &[&str],
+) {
+    let scopes = scopes.join("+").replace(':', "%3A");
 
     let open_params = format!(
-        "response_type=code&client_id={}&redirect_uri=http://localhost:{}/auth&scope={}",
-        client_id, port, scopes
+        "response_type=code&client_id={}&redirect_uri=http://localhost:{}/auth&scope={}&state={}",
+        client_id, port, scopes, oauth_state
     );
 
     let _ = open::that(format!(
-        "https://id.twitch.tv/oauth2/authorize?{}",
-        open_params
+        "{}/oauth2/authorize?{}",
+        TWITCH_ID_BASE_URL, open_params
     ));
 }
 
-pub async fn open_browser_and_authenticate_spotify(client_id: String, port: u16) {
-    if tokio::fs::metadata("spotify_token.json").await.is_ok() {
-        return;
-    }
+pub async fn open_browser_and_authenticate_spotify(
+    client_id: String,
+    port: u16,
+    oauth_state: &str,
+    code_challenge: Option<String>,
+    scopes: &[&str],
+) {
+    let scopes = scopes.join(" ");
 
-    let scopes = "user-modify-playback-state user-read-playback-state";
-
-    let open_params = format!(
-        "response_type=code&client_id={}&redirect_uri=http://localhost:{}/spotify-auth&scope={}",
-        client_id, port, scopes
+    let mut open_params = format!(
+        "response_type=code&client_id={}&redirect_uri=http://localhost:{}/spotify-auth&scope={}&state={}",
+        client_id, port, scopes, oauth_state
     );
 
+    if let Some(code_challenge) = code_challenge {
+        open_params.push_str(&format!(
+            "&code_challenge_method=S256&code_challenge={}",
+            code_challenge
+        ));
+    }
+
     let _ = open::that(format!(
-        "https://accounts.spotify.com/authorize?{}",
-        open_params
+        "{}/authorize?{}",
+        SPOTIFY_ACCOUNTS_BASE_URL, open_params
     ));
 }
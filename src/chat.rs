@@ -0,0 +1,403 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::sync::mpsc::UnboundedReceiver;
+use twitch_irc::{
+    login::LoginCredentials,
+    message::{PrivmsgMessage, ServerMessage},
+    transport::Transport,
+    TwitchIRCClient,
+};
+
+use crate::{
+    error::{TwitchBotError, TwitchBotResult},
+    helix::HelixClient,
+    outgoing::ModChannels,
+};
+
+/// A chat message with just what the commands need, so they don't depend on the chat platform
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub channel: String,
+    pub message_id: String,
+    pub sender_id: String,
+    pub sender_login: String,
+    pub sender_name: String,
+    pub text: String,
+    pub badges: Vec<String>,
+    pub emote_count: usize,
+}
+
+impl ChatMessage {
+    /// A message typed locally, e.g. in the REPL, with a random id so it can be replied to
+    pub fn local(channel: &str, sender_name: &str, badges: &[String], text: &str) -> Self {
+        let message_id: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+
+        Self {
+            channel: channel.to_lowercase(),
+            message_id,
+            sender_id: sender_name.to_lowercase(),
+            sender_login: sender_name.to_lowercase(),
+            sender_name: sender_name.to_string(),
+            text: text.to_string(),
+            badges: badges.to_vec(),
+            emote_count: 0,
+        }
+    }
+
+    pub fn is_mod(&self) -> bool {
+        self.badges
+            .iter()
+            .any(|badge| badge == "moderator" || badge == "broadcaster")
+    }
+}
+
+impl From<PrivmsgMessage> for ChatMessage {
+    fn from(msg: PrivmsgMessage) -> Self {
+        Self {
+            channel: msg.channel_login,
+            message_id: msg.message_id,
+            sender_id: msg.sender.id,
+            sender_login: msg.sender.login,
+            sender_name: msg.sender.name,
+            text: msg.message_text,
+            badges: msg.badges.into_iter().map(|badge| badge.name).collect(),
+            emote_count: msg.emotes.len(),
+        }
+    }
+}
+
+/// Where the bot's chat messages and moderation actions go, Twitch or a local console
+#[async_trait]
+pub trait ChatSender: Send + Sync {
+    async fn say(&self, channel: String, message: String) -> TwitchBotResult<()>;
+
+    /// Sends the message as an action, like typing /me
+    async fn me(&self, channel: String, message: String) -> TwitchBotResult<()>;
+
+    /// Answers a message so it shows up threaded under it
+    async fn reply(&self, parent: &ChatMessage, message: String) -> TwitchBotResult<()>;
+
+    async fn whisper(&self, user: String, message: String) -> TwitchBotResult<()>;
+
+    async fn delete(&self, message: &ChatMessage) -> TwitchBotResult<()>;
+
+    async fn timeout(
+        &self,
+        channel: String,
+        user_id: String,
+        duration: Duration,
+        reason: Option<String>,
+    ) -> TwitchBotResult<()>;
+}
+
+/// Where the bot's chat messages come from
+#[async_trait]
+pub trait ChatReceiver: Send {
+    /// Waits for the next chat message, None once the connection is closed
+    async fn recv(&mut self) -> Option<ChatMessage>;
+}
+
+/// Chat messages go over IRC, moderation goes through the Helix API
+pub struct TwitchChat<T: Transport, L: LoginCredentials> {
+    irc: TwitchIRCClient<T, L>,
+    helix: HelixClient,
+}
+
+impl<T: Transport, L: LoginCredentials> TwitchChat<T, L> {
+    pub fn new(irc: TwitchIRCClient<T, L>, helix: HelixClient) -> Self {
+        Self { irc, helix }
+    }
+}
+
+#[async_trait]
+impl<T: Transport, L: LoginCredentials> ChatSender for TwitchChat<T, L> {
+    async fn say(&self, channel: String, message: String) -> TwitchBotResult<()> {
+        self.irc
+            .privmsg(channel, message)
+            .await
+            .map_err(|e| TwitchBotError::ChatError(e.to_string()))
+    }
+
+    async fn me(&self, channel: String, message: String) -> TwitchBotResult<()> {
+        self.irc
+            .me(channel, message)
+            .await
+            .map_err(|e| TwitchBotError::ChatError(e.to_string()))
+    }
+
+    async fn reply(&self, parent: &ChatMessage, message: String) -> TwitchBotResult<()> {
+        let parent = (parent.channel.as_str(), parent.message_id.as_str());
+
+        self.irc
+            .say_in_reply_to(&parent, message)
+            .await
+            .map_err(|e| TwitchBotError::ChatError(e.to_string()))
+    }
+
+    //Whispers need a phone verified account and their own scope, the bot doesn't send any
+    async fn whisper(&self, _user: String, _message: String) -> TwitchBotResult<()> {
+        Err(TwitchBotError::UnsupportedByChat("whispers"))
+    }
+
+    async fn delete(&self, message: &ChatMessage) -> TwitchBotResult<()> {
+        self.helix
+            .delete_message_async(&message.channel, &message.message_id)
+            .await
+    }
+
+    async fn timeout(
+        &self,
+        channel: String,
+        user_id: String,
+        duration: Duration,
+        reason: Option<String>,
+    ) -> TwitchBotResult<()> {
+        self.helix
+            .timeout_user_async(&channel, &user_id, duration, reason.as_deref())
+            .await
+    }
+}
+
+/// Twitch sends everything over the same connection, chat messages are passed on and the
+/// bot's own USERSTATE keeps track of where it's a moderator
+pub struct TwitchReceiver {
+    incoming_messages: UnboundedReceiver<ServerMessage>,
+    mod_channels: ModChannels,
+}
+
+impl TwitchReceiver {
+    pub fn new(
+        incoming_messages: UnboundedReceiver<ServerMessage>,
+        mod_channels: ModChannels,
+    ) -> Self {
+        Self {
+            incoming_messages,
+            mod_channels,
+        }
+    }
+}
+
+#[async_trait]
+impl ChatReceiver for TwitchReceiver {
+    async fn recv(&mut self) -> Option<ChatMessage> {
+        loop {
+            match self.incoming_messages.recv().await? {
+                ServerMessage::Privmsg(msg) => return Some(msg.into()),
+                ServerMessage::UserState(state) => {
+                    let is_mod = state
+                        .badges
+                        .iter()
+                        .any(|badge| badge.name == "moderator" || badge.name == "broadcaster");
+                    self.mod_channels.set(&state.channel_login, is_mod);
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Messages pushed into the channel by hand, e.g. by the in-memory transport
+#[async_trait]
+impl ChatReceiver for UnboundedReceiver<ChatMessage> {
+    async fn recv(&mut self) -> Option<ChatMessage> {
+        UnboundedReceiver::recv(self).await
+    }
+}
+
+/// Everything the bot can do in chat, as recorded by the in-memory transport
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatAction {
+    Say {
+        channel: String,
+        message: String,
+    },
+    Me {
+        channel: String,
+        message: String,
+    },
+    Reply {
+        channel: String,
+        parent_id: String,
+        message: String,
+    },
+    Whisper {
+        user: String,
+        message: String,
+    },
+    Delete {
+        channel: String,
+        message_id: String,
+    },
+    Timeout {
+        channel: String,
+        user_id: String,
+        duration: Duration,
+        reason: Option<String>,
+    },
+}
+
+impl fmt::Display for ChatAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Say { channel, message } => write!(f, "[bot -> #{}] {}", channel, message),
+            Self::Me { channel, message } => write!(f, "[bot -> #{}] * {}", channel, message),
+            Self::Reply {
+                channel, message, ..
+            } => write!(f, "[bot -> #{}] (reply) {}", channel, message),
+            Self::Whisper { user, message } => write!(f, "[bot -> {}] (whisper) {}", user, message),
+            Self::Delete { channel, .. } => write!(f, "[bot -> #{}] (deleted a message)", channel),
+            Self::Timeout {
+                channel,
+                user_id,
+                duration,
+                reason,
+            } => write!(
+                f,
+                "[bot -> #{}] (timed out {} for {}s{})",
+                channel,
+                user_id,
+                duration.as_secs(),
+                reason
+                    .as_ref()
+                    .map(|reason| format!(": {}", reason))
+                    .unwrap_or_default()
+            ),
+        }
+    }
+}
+
+fn reply_action(parent: &ChatMessage, message: String) -> ChatAction {
+    ChatAction::Reply {
+        channel: parent.channel.clone(),
+        parent_id: parent.message_id.clone(),
+        message,
+    }
+}
+
+fn delete_action(message: &ChatMessage) -> ChatAction {
+    ChatAction::Delete {
+        channel: message.channel.clone(),
+        message_id: message.message_id.clone(),
+    }
+}
+
+/// In-memory transport, keeps everything the bot sends so it can be inspected afterwards
+#[derive(Debug, Clone, Default)]
+pub struct MemoryChat {
+    sent: Arc<Mutex<Vec<ChatAction>>>,
+}
+
+impl MemoryChat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the actions sent so far and clears them
+    pub fn take_sent(&self) -> Vec<ChatAction> {
+        std::mem::take(&mut *self.sent.lock().expect("memory chat lock poisoned"))
+    }
+
+    fn push(&self, action: ChatAction) -> TwitchBotResult<()> {
+        self.sent
+            .lock()
+            .expect("memory chat lock poisoned")
+            .push(action);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ChatSender for MemoryChat {
+    async fn say(&self, channel: String, message: String) -> TwitchBotResult<()> {
+        self.push(ChatAction::Say { channel, message })
+    }
+
+    async fn me(&self, channel: String, message: String) -> TwitchBotResult<()> {
+        self.push(ChatAction::Me { channel, message })
+    }
+
+    async fn reply(&self, parent: &ChatMessage, message: String) -> TwitchBotResult<()> {
+        self.push(reply_action(parent, message))
+    }
+
+    async fn whisper(&self, user: String, message: String) -> TwitchBotResult<()> {
+        self.push(ChatAction::Whisper { user, message })
+    }
+
+    async fn delete(&self, message: &ChatMessage) -> TwitchBotResult<()> {
+        self.push(delete_action(message))
+    }
+
+    async fn timeout(
+        &self,
+        channel: String,
+        user_id: String,
+        duration: Duration,
+        reason: Option<String>,
+    ) -> TwitchBotResult<()> {
+        self.push(ChatAction::Timeout {
+            channel,
+            user_id,
+            duration,
+            reason,
+        })
+    }
+}
+
+/// Prints messages to stdout, used by the offline chat simulator
+pub struct ConsoleChat;
+
+#[async_trait]
+impl ChatSender for ConsoleChat {
+    async fn say(&self, channel: String, message: String) -> TwitchBotResult<()> {
+        println!("{}", ChatAction::Say { channel, message });
+        Ok(())
+    }
+
+    async fn me(&self, channel: String, message: String) -> TwitchBotResult<()> {
+        println!("{}", ChatAction::Me { channel, message });
+        Ok(())
+    }
+
+    async fn reply(&self, parent: &ChatMessage, message: String) -> TwitchBotResult<()> {
+        println!("{}", reply_action(parent, message));
+        Ok(())
+    }
+
+    async fn whisper(&self, user: String, message: String) -> TwitchBotResult<()> {
+        println!("{}", ChatAction::Whisper { user, message });
+        Ok(())
+    }
+
+    async fn delete(&self, message: &ChatMessage) -> TwitchBotResult<()> {
+        println!("{}", delete_action(message));
+        Ok(())
+    }
+
+    async fn timeout(
+        &self,
+        channel: String,
+        user_id: String,
+        duration: Duration,
+        reason: Option<String>,
+    ) -> TwitchBotResult<()> {
+        let action = ChatAction::Timeout {
+            channel,
+            user_id,
+            duration,
+            reason,
+        };
+        println!("{}", action);
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Twitch chat bot with song requests")]
+pub struct Cli {
+    /// Config file, defaults to config.toml in the working directory when it exists
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CliCommand {
+    /// Log in and run the bot, the default when no subcommand is given
+    Run,
+    /// Run only the OAuth flow for one service and save its token, replacing any saved one
+    Auth {
+        #[arg(value_enum)]
+        service: AuthService,
+    },
+    /// Validate the config and show what the bot would run with
+    CheckConfig,
+    /// Print every chat command, including the ones from the commands file
+    ListCommands,
+    /// Run a chat message through the command handler offline, with a stub music player
+    TestCommand {
+        /// The chat message, e.g. "!sr never gonna give you up"
+        message: String,
+        /// Who sent the message
+        #[arg(long, default_value = "tester")]
+        user: String,
+        /// Send the message as a moderator
+        #[arg(long = "mod")]
+        is_mod: bool,
+    },
+    /// Chat with the bot from the terminal, with a stub music player instead of Spotify or MPD
+    Repl {
+        /// Who the typed messages are sent as, change it with /user
+        #[arg(long, default_value = "tester")]
+        user: String,
+        /// Comma separated badges, e.g. moderator or broadcaster, change them with /badges
+        #[arg(long, default_value = "")]
+        badges: String,
+        /// Channel the messages are sent to, defaults to the first configured channel
+        #[arg(long)]
+        channel: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AuthService {
+    Twitch,
+    Spotify,
+}
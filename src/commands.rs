@@ -1,7 +1,23 @@
 use chrono::{DateTime, Duration, Utc};
-use std::{collections::HashMap, sync::Mutex};
+use std::{collections::HashMap, path::Path, sync::Mutex};
 
 use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use crate::error::{TwitchBotError, TwitchBotResult};
+
+/// How a command's response is sent to chat
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseMode {
+    /// Threaded under the message that triggered the command
+    #[default]
+    Reply,
+    /// A plain chat message
+    Message,
+    /// A /me action
+    Me,
+}
 
 #[derive(Debug, Clone)]
 pub struct Command {
@@ -10,6 +26,8 @@ pub struct Command {
     pub usage: String,
     pub requires_arguments: bool,
     pub api_call: Option<String>,
+    pub mod_only: bool,
+    pub response_mode: ResponseMode,
     last_called: Option<DateTime<Utc>>,
 }
 
@@ -20,6 +38,7 @@ impl Command {
         usage: String,
         requires_arguments: bool,
         api_call: Option<String>,
+        mod_only: bool,
     ) -> Self {
         Self {
             response,
@@ -27,19 +46,46 @@ impl Command {
             usage,
             requires_arguments,
             api_call,
+            mod_only,
+            response_mode: ResponseMode::default(),
             last_called: None,
         }
     }
 
+    pub fn with_response_mode(mut self, response_mode: ResponseMode) -> Self {
+        self.response_mode = response_mode;
+        self
+    }
+
     pub fn update_last_called(&mut self) {
         self.last_called = Some(Utc::now());
     }
 }
 
-pub fn get_command(command_text: String, arguments_passed: bool) -> Option<Command> {
+/// A message posted every few minutes while the channel is live
+#[derive(Debug, Clone)]
+pub struct Timer {
+    pub message: String,
+    pub interval_minutes: u32,
+    /// Chat messages that must have been sent since the timer last fired
+    pub min_chat_messages: u32,
+    pub enabled: bool,
+}
+
+impl Timer {
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(u64::from(self.interval_minutes) * 60)
+    }
+}
+
+pub fn get_command(command_text: String, arguments_passed: bool, is_mod: bool) -> Option<Command> {
     let mut map = COMMANDS.lock().unwrap();
 
     if let Some(command) = map.get_mut(command_text.as_str()) {
+        if command.mod_only && !is_mod {
+            return None;
+        }
+
         if command.requires_arguments && !arguments_passed {
             return Some(command.clone());
         }
@@ -60,33 +106,360 @@ pub fn get_command(command_text: String, arguments_passed: bool) -> Option<Comma
     None
 }
 
+/// Every registered command with its name, sorted by name
+pub fn list_commands() -> Vec<(String, Command)> {
+    let map = COMMANDS.lock().unwrap();
+
+    let mut commands: Vec<(String, Command)> = map
+        .iter()
+        .map(|(name, command)| (name.clone(), command.clone()))
+        .collect();
+    commands.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    commands
+}
+
+/// Every configured timer with its name, sorted by name
+pub fn list_timers() -> Vec<(String, Timer)> {
+    let map = TIMERS.lock().unwrap();
+
+    let mut timers: Vec<(String, Timer)> = map
+        .iter()
+        .map(|(name, timer)| (name.clone(), timer.clone()))
+        .collect();
+    timers.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    timers
+}
+
+/// Turns a timer on or off, false if there's no timer with that name
+pub fn set_timer_enabled(name: &str, enabled: bool) -> bool {
+    let mut map = TIMERS.lock().unwrap();
+
+    match map.get_mut(&name.to_lowercase()) {
+        Some(timer) => {
+            timer.enabled = enabled;
+            true
+        }
+        None => false,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CommandsFile {
+    #[serde(default)]
+    commands: Vec<CommandDefinition>,
+    #[serde(default)]
+    timers: Vec<TimerDefinition>,
+}
+
+/// A text command from the commands file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CommandDefinition {
+    name: String,
+    response: String,
+    #[serde(default)]
+    timeout_seconds: u32,
+    #[serde(default)]
+    mod_only: bool,
+    #[serde(default)]
+    response_mode: ResponseMode,
+}
+
+/// A timer from the commands file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimerDefinition {
+    name: String,
+    message: String,
+    interval_minutes: u32,
+    #[serde(default)]
+    min_chat_messages: u32,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// Adds the `[[commands]]` and `[[timers]]` from a TOML file, replacing built in commands with
+/// the same name. Returns how many commands and timers were loaded.
+pub fn load_commands_file(path: &Path) -> TwitchBotResult<(usize, usize)> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        TwitchBotError::InvalidConfig(format!("could not read {}: {}", path.display(), e))
+    })?;
+
+    let file: CommandsFile = toml::from_str(&contents)
+        .map_err(|e| TwitchBotError::InvalidConfig(format!("{}: {}", path.display(), e)))?;
+
+    for definition in &file.commands {
+        if !definition.name.starts_with('!') || definition.name.contains(char::is_whitespace) {
+            return Err(TwitchBotError::InvalidConfig(format!(
+                "{}: command {} must start with ! and have no spaces",
+                path.display(),
+                definition.name
+            )));
+        }
+    }
+
+    for definition in &file.timers {
+        if definition.name.is_empty() || definition.name.contains(char::is_whitespace) {
+            return Err(TwitchBotError::InvalidConfig(format!(
+                "{}: timer {} must have a name without spaces",
+                path.display(),
+                definition.name
+            )));
+        }
+
+        if definition.interval_minutes == 0 {
+            return Err(TwitchBotError::InvalidConfig(format!(
+                "{}: timer {} needs an interval_minutes of at least 1",
+                path.display(),
+                definition.name
+            )));
+        }
+    }
+
+    let mut map = COMMANDS.lock().unwrap();
+    let count = (file.commands.len(), file.timers.len());
+
+    for definition in file.commands {
+        map.insert(
+            definition.name.to_lowercase(),
+            Command::new(
+                definition.response,
+                definition.timeout_seconds,
+                "".to_string(),
+                false,
+                None,
+                definition.mod_only,
+            )
+            .with_response_mode(definition.response_mode),
+        );
+    }
+
+    let mut timers = TIMERS.lock().unwrap();
+
+    for definition in file.timers {
+        timers.insert(
+            definition.name.to_lowercase(),
+            Timer {
+                message: definition.message,
+                interval_minutes: definition.interval_minutes,
+                min_chat_messages: definition.min_chat_messages,
+                enabled: definition.enabled,
+            },
+        );
+    }
+
+    Ok(count)
+}
+
 //Add a "!hi" command for testing
 lazy_static! {
-    static ref COMMANDS: Mutex<HashMap<&'static str, Command>> = {
+    static ref COMMANDS: Mutex<HashMap<String, Command>> = {
         let mut commands = HashMap::new();
 
         commands.insert(
-            "!github",
+            "!github".to_string(),
             Command::new(
                 "https://github.com/vininew921".to_string(),
                 60,
                 "".to_string(),
                 false,
                 None,
+                false,
             ),
         );
 
         commands.insert(
-            "!sr",
+            "!sr".to_string(),
             Command::new(
                 "Musica <song> adicionada a fila".to_string(),
                 30,
                 "Song request: !sr nome da musica".to_string(),
                 true,
                 Some("play_track".to_string()),
+                false,
+            ),
+        );
+
+        commands.insert(
+            "!playlist".to_string(),
+            Command::new(
+                "Playlist alterada para <playlist>".to_string(),
+                0,
+                "Playlist: !playlist nome".to_string(),
+                true,
+                Some("set_playlist".to_string()),
+                true,
             ),
         );
 
+        commands.insert(
+            "!song".to_string(),
+            Command::new(
+                "Tocando agora: <song>".to_string(),
+                10,
+                "".to_string(),
+                false,
+                Some("now_playing".to_string()),
+                false,
+            ),
+        );
+
+        commands.insert(
+            "!skip".to_string(),
+            Command::new(
+                "Musica <song> pulada".to_string(),
+                5,
+                "".to_string(),
+                false,
+                Some("skip_track".to_string()),
+                true,
+            ),
+        );
+
+        commands.insert(
+            "!lastsongs".to_string(),
+            Command::new(
+                "Ultimas musicas: <songs>".to_string(),
+                30,
+                "".to_string(),
+                false,
+                Some("last_songs".to_string()),
+                false,
+            ),
+        );
+
+        commands.insert(
+            "!mysongs".to_string(),
+            Command::new(
+                "Suas ultimas musicas: <songs>".to_string(),
+                10,
+                "".to_string(),
+                false,
+                Some("my_songs".to_string()),
+                false,
+            ),
+        );
+
+        commands.insert(
+            "!topsongs".to_string(),
+            Command::new(
+                "Mais pedidas: <songs>".to_string(),
+                60,
+                "".to_string(),
+                false,
+                Some("top_songs".to_string()),
+                false,
+            ),
+        );
+
+        commands.insert(
+            "!timer".to_string(),
+            Command::new(
+                "Timer <timer> <state>".to_string(),
+                0,
+                "Timer: !timer on|off nome".to_string(),
+                true,
+                Some("set_timer".to_string()),
+                true,
+            ),
+        );
+
+        commands.insert(
+            "!permit".to_string(),
+            Command::new(
+                "<user> pode mandar um link nos proximos <seconds> segundos".to_string(),
+                0,
+                "Permit: !permit usuario".to_string(),
+                true,
+                Some("permit_link".to_string()),
+                true,
+            ),
+        );
+
+        commands.insert(
+            "!uptime".to_string(),
+            Command::new(
+                "No ar ha <uptime>".to_string(),
+                10,
+                "".to_string(),
+                false,
+                Some("uptime".to_string()),
+                false,
+            ),
+        );
+
+        commands.insert(
+            "!game".to_string(),
+            Command::new(
+                "Categoria: <game>".to_string(),
+                10,
+                "".to_string(),
+                false,
+                Some("game".to_string()),
+                false,
+            ),
+        );
+
+        commands.insert(
+            "!title".to_string(),
+            Command::new(
+                "Titulo: <title>".to_string(),
+                10,
+                "".to_string(),
+                false,
+                Some("title".to_string()),
+                false,
+            ),
+        );
+
+        commands.insert(
+            "!settitle".to_string(),
+            Command::new(
+                "Titulo alterado para: <title>".to_string(),
+                0,
+                "Titulo: !settitle novo titulo".to_string(),
+                true,
+                Some("set_title".to_string()),
+                true,
+            ),
+        );
+
+        commands.insert(
+            "!setgame".to_string(),
+            Command::new(
+                "Categoria alterada para: <game>".to_string(),
+                0,
+                "Categoria: !setgame nome da categoria".to_string(),
+                true,
+                Some("set_game".to_string()),
+                true,
+            ),
+        );
+
+        commands.insert(
+            "!so".to_string(),
+            Command::new(
+                "Sigam <user>, que estava jogando <game>! https://twitch.tv/<login><clip>"
+                    .to_string(),
+                0,
+                "Shoutout: !so usuario".to_string(),
+                true,
+                Some("shoutout".to_string()),
+                true,
+            )
+            .with_response_mode(ResponseMode::Message),
+        );
+
         Mutex::new(commands)
     };
+
+    //Timers only come from the commands file
+    static ref TIMERS: Mutex<HashMap<String, Timer>> = Mutex::new(HashMap::new());
 }
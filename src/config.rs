@@ -0,0 +1,527 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    error::{TwitchBotError, TwitchBotResult},
+    helix::TWITCH_HELIX_BASE_URL,
+    moderation::ModerationConfig,
+    music::{
+        playlist::{PlaylistMode, RequestsPlaylistMode},
+        MusicBackend,
+    },
+    scopes::Features,
+    secret::{Secret, SecretKind},
+    spotify::client::playlist_id,
+    token_store::TokenStore,
+    twitch_auth::{TwitchAuthFlow, TWITCH_ID_BASE_URL},
+};
+
+/// Read from the working directory when --config isn't passed
+pub const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// Everything the bot can be configured with. Loaded from a TOML file, then overridden by the
+/// environment variables listed on each field, so existing `.env` setups keep working.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub twitch: TwitchConfig,
+    pub server: ServerConfig,
+    pub music: MusicConfig,
+    pub spotify: SpotifyConfig,
+    pub storage: StorageConfig,
+    pub features: Features,
+    /// Chat filters, used with features.moderation
+    pub moderation: ModerationConfig,
+    /// Extra chat commands, COMMANDS_FILE
+    pub commands_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TwitchConfig {
+    /// TWITCH_CLIENT_ID
+    pub client_id: String,
+    /// Only optional for the device login, TWITCH_CLIENT_SECRET
+    pub client_secret: Option<Secret>,
+    /// TWITCH_AUTH_FLOW, browser or device
+    pub auth_flow: TwitchAuthFlow,
+    /// TWITCH_CHANNELS, comma separated
+    pub channels: Vec<String>,
+    /// How long to wait for a browser login before giving up, AUTH_TIMEOUT_SECONDS
+    pub auth_timeout_seconds: u64,
+    /// Twitch API, overridable to point at a mock server, TWITCH_HELIX_BASE_URL
+    pub helix_base_url: String,
+    /// Twitch token endpoints, overridable to point at a mock server, TWITCH_ID_BASE_URL
+    pub id_base_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address of the local OAuth callback server, BIND_ADDRESS
+    pub bind_address: String,
+    /// PORT, must match the redirect URLs registered for the Twitch and Spotify apps
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MusicConfig {
+    /// MUSIC_BACKEND, spotify or mpd
+    pub backend: MusicBackend,
+    /// MPD_ADDRESS
+    pub mpd_address: String,
+    /// MPD_PASSWORD
+    pub mpd_password: Option<Secret>,
+    /// mpv IPC socket used for YouTube requests, MPV_SOCKET
+    pub mpv_socket: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpotifyConfig {
+    /// SPOTIFY_CLIENT_ID
+    pub client_id: String,
+    /// Without a secret the PKCE flow is used, SPOTIFY_SECRET
+    pub client_secret: Option<Secret>,
+    /// Fallback playlists by name, playlist ids or share URLs, SPOTIFY_PLAYLISTS="name=id,..."
+    pub playlists: HashMap<String, String>,
+    /// SPOTIFY_PLAYLIST
+    pub default_playlist: Option<String>,
+    /// SPOTIFY_REQUESTS_PLAYLIST, rolling or stream
+    pub requests_playlist_mode: RequestsPlaylistMode,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// TOKEN_DIR
+    pub token_dir: PathBuf,
+    /// TOKEN_ENCRYPTION_KEY
+    pub token_encryption_key: Option<Secret>,
+    /// SONG_HISTORY_DB
+    pub history_db: PathBuf,
+}
+
+impl Default for TwitchConfig {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: None,
+            auth_flow: TwitchAuthFlow::Browser,
+            channels: vec!["vynny_".to_string()],
+            auth_timeout_seconds: 300,
+            helix_base_url: TWITCH_HELIX_BASE_URL.to_string(),
+            id_base_url: TWITCH_ID_BASE_URL.to_string(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "127.0.0.1".to_string(),
+            port: 42069,
+        }
+    }
+}
+
+impl Default for MusicConfig {
+    fn default() -> Self {
+        Self {
+            backend: MusicBackend::Spotify,
+            mpd_address: "127.0.0.1:6600".to_string(),
+            mpd_password: None,
+            mpv_socket: None,
+        }
+    }
+}
+
+impl Default for SpotifyConfig {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: None,
+            playlists: HashMap::new(),
+            default_playlist: None,
+            requests_playlist_mode: RequestsPlaylistMode::Rolling,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            token_dir: PathBuf::from("."),
+            token_encryption_key: None,
+            history_db: PathBuf::from("song_history.db"),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the given file, or config.toml if it exists, applies the environment and validates
+    pub fn load(path: Option<&Path>) -> TwitchBotResult<Self> {
+        let config = Self::read(path)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Like [`Config::load`] without validating, for tools that don't need credentials
+    pub fn read(path: Option<&Path>) -> TwitchBotResult<Self> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_env()?;
+        config.register_secrets();
+
+        Ok(config)
+    }
+
+    /// Keeps every configured secret out of the logs, however short it is
+    fn register_secrets(&self) {
+        let secrets = [
+            (SecretKind::TwitchClientSecret, &self.twitch.client_secret),
+            (SecretKind::SpotifyClientSecret, &self.spotify.client_secret),
+            (SecretKind::MpdPassword, &self.music.mpd_password),
+            (
+                SecretKind::TokenEncryptionKey,
+                &self.storage.token_encryption_key,
+            ),
+        ];
+
+        for (kind, secret) in secrets {
+            if let Some(secret) = secret {
+                secret.register(kind);
+            }
+        }
+    }
+
+    pub fn from_file(path: &Path) -> TwitchBotResult<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            TwitchBotError::InvalidConfig(format!("could not read {}: {}", path.display(), e))
+        })?;
+
+        toml::from_str(&contents)
+            .map_err(|e| TwitchBotError::InvalidConfig(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Environment variables win over the file
+    pub fn apply_env(&mut self) -> TwitchBotResult<()> {
+        if let Some(client_id) = env("TWITCH_CLIENT_ID") {
+            self.twitch.client_id = client_id;
+        }
+        if let Some(client_secret) = env("TWITCH_CLIENT_SECRET") {
+            self.twitch.client_secret = Some(client_secret.into());
+        }
+        if let Some(auth_flow) = env("TWITCH_AUTH_FLOW") {
+            self.twitch.auth_flow = auth_flow.parse()?;
+        }
+        if let Some(channels) = env("TWITCH_CHANNELS") {
+            self.twitch.channels = channels
+                .split(',')
+                .map(|channel| channel.trim().to_string())
+                .filter(|channel| !channel.is_empty())
+                .collect();
+        }
+        if let Some(seconds) = env("AUTH_TIMEOUT_SECONDS") {
+            self.twitch.auth_timeout_seconds = parse_env("AUTH_TIMEOUT_SECONDS", &seconds)?;
+        }
+        if let Some(helix_base_url) = env("TWITCH_HELIX_BASE_URL") {
+            self.twitch.helix_base_url = helix_base_url;
+        }
+        if let Some(id_base_url) = env("TWITCH_ID_BASE_URL") {
+            self.twitch.id_base_url = id_base_url;
+        }
+
+        if let Some(bind_address) = env("BIND_ADDRESS") {
+            self.server.bind_address = bind_address;
+        }
+        if let Some(port) = env("PORT") {
+            self.server.port = parse_env("PORT", &port)?;
+        }
+
+        if let Some(backend) = env("MUSIC_BACKEND") {
+            self.music.backend = backend.parse()?;
+        }
+        if let Some(mpd_address) = env("MPD_ADDRESS") {
+            self.music.mpd_address = mpd_address;
+        }
+        if let Some(mpd_password) = env("MPD_PASSWORD") {
+            self.music.mpd_password = Some(mpd_password.into());
+        }
+        if let Some(mpv_socket) = env("MPV_SOCKET") {
+            self.music.mpv_socket = Some(mpv_socket);
+            self.features.youtube_requests = true;
+        }
+
+        if let Some(client_id) = env("SPOTIFY_CLIENT_ID") {
+            self.spotify.client_id = client_id;
+        }
+        if let Some(client_secret) = env("SPOTIFY_SECRET") {
+            self.spotify.client_secret = Some(client_secret.into());
+        }
+        if let Some(playlists) = env("SPOTIFY_PLAYLISTS") {
+            self.spotify.playlists = PlaylistMode::parse_playlists(&playlists);
+        }
+        if let Some(default_playlist) = env("SPOTIFY_PLAYLIST") {
+            self.spotify.default_playlist = Some(default_playlist);
+        }
+        if let Some(mode) = env("SPOTIFY_REQUESTS_PLAYLIST") {
+            self.spotify.requests_playlist_mode = mode.parse()?;
+            self.features.requests_playlist = true;
+        }
+
+        if let Some(token_dir) = env("TOKEN_DIR") {
+            self.storage.token_dir = token_dir.into();
+        }
+        if let Some(encryption_key) = env("TOKEN_ENCRYPTION_KEY") {
+            self.storage.token_encryption_key = Some(encryption_key.into());
+        }
+        if let Some(history_db) = env("SONG_HISTORY_DB") {
+            self.storage.history_db = history_db.into();
+        }
+
+        if let Some(commands_file) = env("COMMANDS_FILE") {
+            self.commands_file = Some(commands_file.into());
+        }
+
+        Ok(())
+    }
+
+    /// Collects every problem instead of stopping at the first one
+    pub fn validate(&self) -> TwitchBotResult<()> {
+        let mut problems = Vec::new();
+
+        if self.twitch.client_id.is_empty() {
+            problems.push("twitch.client_id is required (TWITCH_CLIENT_ID)".to_string());
+        }
+        //Device code logins also work for public clients, which don't have a secret
+        if self.twitch.client_secret.is_none() && self.twitch.auth_flow == TwitchAuthFlow::Browser {
+            problems.push(
+                "twitch.client_secret is required for the browser login (TWITCH_CLIENT_SECRET)"
+                    .to_string(),
+            );
+        }
+        if self.twitch.channels.is_empty() {
+            problems
+                .push("twitch.channels needs at least one channel (TWITCH_CHANNELS)".to_string());
+        }
+        for channel in &self.twitch.channels {
+            let valid = channel
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                problems.push(format!(
+                    "twitch.channels: {} is not a channel name, use the login without #",
+                    channel
+                ));
+            }
+        }
+        if self.twitch.auth_timeout_seconds == 0 {
+            problems.push("twitch.auth_timeout_seconds must be greater than 0".to_string());
+        }
+
+        if self.server.port == 0 {
+            problems.push("server.port must be greater than 0 (PORT)".to_string());
+        }
+
+        match self.music.backend {
+            MusicBackend::Spotify if self.spotify.client_id.is_empty() => {
+                problems.push(
+                    "spotify.client_id is required for the spotify backend (SPOTIFY_CLIENT_ID)"
+                        .to_string(),
+                );
+            }
+            MusicBackend::Mpd if self.music.mpd_address.is_empty() => {
+                problems.push("music.mpd_address is required for the mpd backend".to_string());
+            }
+            _ => (),
+        }
+
+        if self.features.youtube_requests && self.music.mpv_socket.is_none() {
+            problems
+                .push("features.youtube_requests needs music.mpv_socket (MPV_SOCKET)".to_string());
+        }
+
+        if let Some(default_playlist) = &self.spotify.default_playlist {
+            if !self
+                .playlists()
+                .contains_key(&default_playlist.to_lowercase())
+            {
+                problems.push(format!(
+                    "spotify.default_playlist {} is not one of spotify.playlists",
+                    default_playlist
+                ));
+            }
+        }
+
+        if self.features.moderation && !self.moderation.has_filters() {
+            problems.push(
+                "features.moderation needs at least one filter, e.g. [moderation.links]"
+                    .to_string(),
+            );
+        }
+        self.moderation.validate(&mut problems);
+
+        if let Some(commands_file) = &self.commands_file {
+            if !commands_file.is_file() {
+                problems.push(format!(
+                    "commands_file {} does not exist",
+                    commands_file.display()
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(TwitchBotError::InvalidConfig(format!(
+                "\n  - {}",
+                problems.join("\n  - ")
+            )))
+        }
+    }
+
+    pub fn auth_timeout(&self) -> Duration {
+        Duration::from_secs(self.twitch.auth_timeout_seconds)
+    }
+
+    pub fn spotify_scopes(&self) -> Vec<&'static str> {
+        self.features
+            .spotify_scopes(!self.spotify.playlists.is_empty())
+    }
+
+    pub fn twitch_client_secret(&self) -> String {
+        self.twitch
+            .client_secret
+            .as_ref()
+            .map(|client_secret| client_secret.expose().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Fallback playlists with lowercased names. Spotify share URLs are resolved to ids, other
+    /// backends get the playlist name as written.
+    pub fn playlists(&self) -> HashMap<String, String> {
+        self.spotify
+            .playlists
+            .iter()
+            .map(|(name, playlist)| {
+                let playlist = match self.music.backend {
+                    MusicBackend::Spotify => playlist_id(playlist.trim()),
+                    MusicBackend::Mpd => playlist.trim().to_string(),
+                };
+
+                (name.trim().to_lowercase(), playlist)
+            })
+            .filter(|(name, playlist)| !name.is_empty() && !playlist.is_empty())
+            .collect()
+    }
+
+    pub fn token_store(&self) -> TokenStore {
+        TokenStore::new(
+            &self.storage.token_dir,
+            self.storage
+                .token_encryption_key
+                .as_ref()
+                .map(|key| key.expose()),
+        )
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+fn parse_env<T: FromStr>(name: &str, value: &str) -> TwitchBotResult<T> {
+    value
+        .parse()
+        .map_err(|_| TwitchBotError::InvalidConfig(format!("invalid {} {}", name, value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(backend: MusicBackend) -> Config {
+        let mut config = Config::default();
+        config.music.backend = backend;
+        config.spotify.playlists = HashMap::from([
+            (
+                " Chill ".to_string(),
+                "https://open.spotify.com/playlist/abc?si=x".to_string(),
+            ),
+            ("rock".to_string(), "Rock: 80s/90s".to_string()),
+        ]);
+        config
+    }
+
+    fn twitch_config(auth_flow: TwitchAuthFlow) -> Config {
+        let mut config = Config::default();
+        config.twitch.client_id = "client-id".to_string();
+        config.twitch.channels = vec!["channel".to_string()];
+        config.twitch.auth_flow = auth_flow;
+        config.spotify.client_id = "client-id".to_string();
+        config
+    }
+
+    #[test]
+    fn spotify_playlist_scope_only_with_playlists() {
+        let scopes = Config::default().spotify_scopes();
+        assert!(!scopes.contains(&"playlist-read-private"));
+
+        let scopes = config(MusicBackend::Spotify).spotify_scopes();
+        assert!(scopes.contains(&"playlist-read-private"));
+    }
+
+    #[test]
+    fn the_browser_login_needs_a_client_secret() {
+        match twitch_config(TwitchAuthFlow::Browser).validate() {
+            Err(TwitchBotError::InvalidConfig(problems)) => {
+                assert!(problems.contains("twitch.client_secret"))
+            }
+            other => panic!("expected a config error, got {:?}", other),
+        }
+
+        let mut config = twitch_config(TwitchAuthFlow::Browser);
+        config.twitch.client_secret = Some("secret".to_string().into());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn the_device_login_works_without_a_client_secret() {
+        twitch_config(TwitchAuthFlow::DeviceCode)
+            .validate()
+            .unwrap();
+    }
+
+    #[test]
+    fn spotify_playlists_are_resolved_to_ids() {
+        let playlists = config(MusicBackend::Spotify).playlists();
+
+        assert_eq!(playlists["chill"], "abc");
+    }
+
+    #[test]
+    fn mpd_playlists_keep_their_names() {
+        let playlists = config(MusicBackend::Mpd).playlists();
+
+        assert_eq!(
+            playlists["chill"],
+            "https://open.spotify.com/playlist/abc?si=x"
+        );
+        assert_eq!(playlists["rock"], "Rock: 80s/90s");
+    }
+}
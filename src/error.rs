@@ -9,6 +9,9 @@ pub enum TwitchBotError {
     #[error(transparent)]
     TwitchIrcValidationError(#[from] twitch_irc::validate::Error),
 
+    #[error(transparent)]
+    SqliteError(#[from] rusqlite::Error),
+
     #[error(transparent)]
     SerdeJsonError(#[from] serde_json::Error),
 
@@ -17,6 +20,42 @@ pub enum TwitchBotError {
 
     #[error("Could not update Twitch Token")]
     TwitchTokenUpdateError(),
+
+    #[error("Twitch device login failed: {0}")]
+    TwitchDeviceAuthError(String),
+
+    #[error("Could not send chat message: {0}")]
+    ChatError(String),
+
+    #[error("Timed out waiting for the {0} login, restart the bot to try again")]
+    AuthTimeout(&'static str),
+
+    #[error("Stopped waiting for the {0} login, the auth server shut down")]
+    AuthCallbackClosed(&'static str),
+
+    #[error("Could not decrypt token file {0}, check TOKEN_ENCRYPTION_KEY")]
+    TokenDecryptError(String),
+
+    #[error("Could not encrypt token")]
+    TokenEncryptError(),
+
+    #[error("MPD error: {0}")]
+    MpdError(String),
+
+    #[error("mpv error: {0}")]
+    MpvError(String),
+
+    #[error("The music backend does not support {0}")]
+    UnsupportedByMusicBackend(&'static str),
+
+    #[error("Twitch API error: {0}")]
+    HelixError(String),
+
+    #[error("The chat connection does not support {0}")]
+    UnsupportedByChat(&'static str),
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
 }
 
 pub type TwitchBotResult<T, E = TwitchBotError> = anyhow::Result<T, E>;
@@ -0,0 +1,758 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use reqwest::{Method, RequestBuilder, Response};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use twitch_irc::login::{LoginCredentials, RefreshingLoginCredentials};
+
+use crate::{
+    error::{TwitchBotError, TwitchBotResult},
+    twitch_auth::TwitchTokenStorage,
+};
+
+pub const TWITCH_HELIX_BASE_URL: &str = "https://api.twitch.tv/helix";
+
+/// Twitch doesn't allow timeouts longer than two weeks
+const MAX_TIMEOUT_SECONDS: u64 = 1_209_600;
+
+/// Helix wraps every list of results in `data`
+#[derive(Deserialize)]
+struct HelixResponse<T> {
+    data: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct HelixErrorResponse {
+    message: String,
+}
+
+/// A live stream, Helix only returns one while the channel is live
+#[derive(Debug, Clone, Deserialize)]
+pub struct HelixStream {
+    pub user_id: String,
+    pub user_login: String,
+    pub game_name: String,
+    pub title: String,
+    pub started_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HelixUser {
+    pub id: String,
+    pub login: String,
+    pub display_name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HelixChannel {
+    pub broadcaster_id: String,
+    pub broadcaster_login: String,
+    pub game_id: String,
+    pub game_name: String,
+    pub title: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HelixCategory {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HelixClip {
+    pub url: String,
+    pub title: String,
+    pub view_count: u64,
+}
+
+/// Chat settings to change, the ones left as None stay as they are
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChatSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_mode: Option<bool>,
+    /// Seconds between messages, 3 to 120
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_mode_wait_time: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub follower_mode: Option<bool>,
+    /// Minutes someone must have followed for, up to 129600
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub follower_mode_duration: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emote_mode: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnouncementColor {
+    Primary,
+    Blue,
+    Green,
+    Orange,
+    Purple,
+}
+
+/// Twitch API client, using the same token twitch-irc refreshes and saves through
+/// `TwitchTokenStorage`. Moderation calls act as the bot's account, which must be a moderator in
+/// the channel.
+#[derive(Debug, Clone)]
+pub struct HelixClient {
+    client: reqwest::Client,
+    base_url: String,
+    client_id: String,
+    credentials: RefreshingLoginCredentials<TwitchTokenStorage>,
+    /// User ids by login, they never change
+    user_ids: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl HelixClient {
+    pub fn new(
+        client_id: String,
+        credentials: RefreshingLoginCredentials<TwitchTokenStorage>,
+        base_url: String,
+    ) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url,
+            client_id,
+            credentials,
+            user_ids: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The channel's stream, None when it's offline
+    pub async fn get_stream_async(&self, user_login: &str) -> TwitchBotResult<Option<HelixStream>> {
+        let streams: Vec<HelixStream> = self
+            .get_async("streams", &[("user_login", user_login)])
+            .await?;
+
+        Ok(streams.into_iter().next())
+    }
+
+    pub async fn get_user_async(&self, login: &str) -> TwitchBotResult<Option<HelixUser>> {
+        let users: Vec<HelixUser> = self.get_async("users", &[("login", login)]).await?;
+        let user = users.into_iter().next();
+
+        if let Some(user) = &user {
+            self.user_ids
+                .lock()
+                .expect("user ids lock poisoned")
+                .insert(user.login.clone(), user.id.clone());
+        }
+
+        Ok(user)
+    }
+
+    /// Title and category, also while the channel is offline
+    pub async fn get_channel_async(&self, channel: &str) -> TwitchBotResult<Option<HelixChannel>> {
+        let broadcaster_id = self.user_id_async(channel).await?;
+
+        let channels: Vec<HelixChannel> = self
+            .get_async("channels", &[("broadcaster_id", broadcaster_id.as_str())])
+            .await?;
+
+        Ok(channels.into_iter().next())
+    }
+
+    pub async fn search_categories_async(
+        &self,
+        query: &str,
+    ) -> TwitchBotResult<Vec<HelixCategory>> {
+        self.get_async("search/categories", &[("query", query), ("first", "10")])
+            .await
+    }
+
+    /// The most viewed clip created since `started_at`
+    pub async fn get_top_clip_async(
+        &self,
+        broadcaster_id: &str,
+        started_at: DateTime<Utc>,
+    ) -> TwitchBotResult<Option<HelixClip>> {
+        let started_at = started_at.to_rfc3339_opts(SecondsFormat::Secs, true);
+
+        let clips: Vec<HelixClip> = self
+            .get_async(
+                "clips",
+                &[
+                    ("broadcaster_id", broadcaster_id),
+                    ("started_at", started_at.as_str()),
+                    ("first", "1"),
+                ],
+            )
+            .await?;
+
+        Ok(clips.into_iter().next())
+    }
+
+    /// Twitch's own shoutout, only while the channel is live. Needs moderator:manage:shoutouts.
+    pub async fn send_shoutout_async(
+        &self,
+        channel: &str,
+        to_user_id: &str,
+    ) -> TwitchBotResult<()> {
+        let (broadcaster_id, moderator_id) = self.moderation_ids_async(channel).await?;
+
+        let request = self.request(Method::POST, "chat/shoutouts").query(&[
+            ("from_broadcaster_id", broadcaster_id.as_str()),
+            ("to_broadcaster_id", to_user_id),
+            ("moderator_id", moderator_id.as_str()),
+        ]);
+        self.send_async(request).await?;
+
+        Ok(())
+    }
+
+    /// Changes the title and/or category. Needs channel:manage:broadcast, and Twitch only allows
+    /// it with the broadcaster's own token, so the bot must be logged in as the channel.
+    pub async fn update_channel_async(
+        &self,
+        channel: &str,
+        title: Option<&str>,
+        game_id: Option<&str>,
+    ) -> TwitchBotResult<()> {
+        let broadcaster_id = self.user_id_async(channel).await?;
+
+        let mut body = json!({});
+        if let Some(title) = title {
+            body["title"] = json!(title);
+        }
+        if let Some(game_id) = game_id {
+            body["game_id"] = json!(game_id);
+        }
+
+        let request = self
+            .request(Method::PATCH, "channels")
+            .query(&[("broadcaster_id", broadcaster_id.as_str())])
+            .json(&body);
+        self.send_async(request).await?;
+
+        Ok(())
+    }
+
+    /// Needs moderator:manage:banned_users
+    pub async fn ban_user_async(
+        &self,
+        channel: &str,
+        user_id: &str,
+        reason: Option<&str>,
+    ) -> TwitchBotResult<()> {
+        self.ban_async(channel, user_id, None, reason).await
+    }
+
+    /// Needs moderator:manage:banned_users
+    pub async fn timeout_user_async(
+        &self,
+        channel: &str,
+        user_id: &str,
+        duration: Duration,
+        reason: Option<&str>,
+    ) -> TwitchBotResult<()> {
+        let seconds = duration.as_secs().clamp(1, MAX_TIMEOUT_SECONDS);
+        self.ban_async(channel, user_id, Some(seconds), reason)
+            .await
+    }
+
+    /// Lifts a ban or a timeout, needs moderator:manage:banned_users
+    pub async fn unban_user_async(&self, channel: &str, user_id: &str) -> TwitchBotResult<()> {
+        let (broadcaster_id, moderator_id) = self.moderation_ids_async(channel).await?;
+
+        let request = self.request(Method::DELETE, "moderation/bans").query(&[
+            ("broadcaster_id", broadcaster_id.as_str()),
+            ("moderator_id", moderator_id.as_str()),
+            ("user_id", user_id),
+        ]);
+        self.send_async(request).await?;
+
+        Ok(())
+    }
+
+    /// Needs moderator:manage:chat_messages
+    pub async fn delete_message_async(
+        &self,
+        channel: &str,
+        message_id: &str,
+    ) -> TwitchBotResult<()> {
+        let (broadcaster_id, moderator_id) = self.moderation_ids_async(channel).await?;
+
+        let request = self.request(Method::DELETE, "moderation/chat").query(&[
+            ("broadcaster_id", broadcaster_id.as_str()),
+            ("moderator_id", moderator_id.as_str()),
+            ("message_id", message_id),
+        ]);
+        self.send_async(request).await?;
+
+        Ok(())
+    }
+
+    /// Slow mode, followers-only and emote-only, needs moderator:manage:chat_settings
+    pub async fn update_chat_settings_async(
+        &self,
+        channel: &str,
+        settings: &ChatSettings,
+    ) -> TwitchBotResult<()> {
+        let (broadcaster_id, moderator_id) = self.moderation_ids_async(channel).await?;
+
+        let request = self
+            .request(Method::PATCH, "chat/settings")
+            .query(&[
+                ("broadcaster_id", broadcaster_id.as_str()),
+                ("moderator_id", moderator_id.as_str()),
+            ])
+            .json(settings);
+        self.send_async(request).await?;
+
+        Ok(())
+    }
+
+    /// Needs moderator:manage:announcements
+    pub async fn send_announcement_async(
+        &self,
+        channel: &str,
+        message: &str,
+        color: Option<AnnouncementColor>,
+    ) -> TwitchBotResult<()> {
+        let (broadcaster_id, moderator_id) = self.moderation_ids_async(channel).await?;
+
+        let request = self
+            .request(Method::POST, "chat/announcements")
+            .query(&[
+                ("broadcaster_id", broadcaster_id.as_str()),
+                ("moderator_id", moderator_id.as_str()),
+            ])
+            .json(&json!({
+                "message": message,
+                "color": color.unwrap_or(AnnouncementColor::Primary),
+            }));
+        self.send_async(request).await?;
+
+        Ok(())
+    }
+
+    async fn ban_async(
+        &self,
+        channel: &str,
+        user_id: &str,
+        duration_seconds: Option<u64>,
+        reason: Option<&str>,
+    ) -> TwitchBotResult<()> {
+        let (broadcaster_id, moderator_id) = self.moderation_ids_async(channel).await?;
+
+        let mut data = json!({ "user_id": user_id });
+        if let Some(duration_seconds) = duration_seconds {
+            data["duration"] = json!(duration_seconds);
+        }
+        if let Some(reason) = reason {
+            data["reason"] = json!(reason);
+        }
+
+        let request = self
+            .request(Method::POST, "moderation/bans")
+            .query(&[
+                ("broadcaster_id", broadcaster_id.as_str()),
+                ("moderator_id", moderator_id.as_str()),
+            ])
+            .json(&json!({ "data": data }));
+        self.send_async(request).await?;
+
+        Ok(())
+    }
+
+    /// The channel's user id and the bot's, which moderation calls act as
+    async fn moderation_ids_async(&self, channel: &str) -> TwitchBotResult<(String, String)> {
+        let bot_login = self
+            .credentials
+            .get_credentials()
+            .await
+            .map_err(|e| TwitchBotError::HelixError(e.to_string()))?
+            .login;
+
+        Ok((
+            self.user_id_async(channel).await?,
+            self.user_id_async(&bot_login).await?,
+        ))
+    }
+
+    async fn user_id_async(&self, login: &str) -> TwitchBotResult<String> {
+        let login = login.to_lowercase();
+
+        let cached = self
+            .user_ids
+            .lock()
+            .expect("user ids lock poisoned")
+            .get(&login)
+            .cloned();
+        if let Some(user_id) = cached {
+            return Ok(user_id);
+        }
+
+        match self.get_user_async(&login).await? {
+            Some(user) => Ok(user.id),
+            None => Err(TwitchBotError::HelixError(format!(
+                "no Twitch user named {}",
+                login
+            ))),
+        }
+    }
+
+    async fn get_async<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> TwitchBotResult<Vec<T>> {
+        let request = self.request(Method::GET, path).query(query);
+        let response = self.send_async(request).await?;
+
+        Ok(response.json::<HelixResponse<T>>().await?.data)
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, format!("{}/{}", self.base_url, path))
+    }
+
+    /// Adds the token and turns error responses into Twitch's own error message
+    async fn send_async(&self, request: RequestBuilder) -> TwitchBotResult<Response> {
+        let response = request
+            .bearer_auth(self.access_token().await?)
+            .header("Client-Id", &self.client_id)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let message = match response.json::<HelixErrorResponse>().await {
+            Ok(error) => error.message,
+            Err(_) => status.to_string(),
+        };
+
+        Err(TwitchBotError::HelixError(format!(
+            "{} ({})",
+            message,
+            status.as_u16()
+        )))
+    }
+
+    async fn access_token(&self) -> TwitchBotResult<String> {
+        let credentials = self
+            .credentials
+            .get_credentials()
+            .await
+            .map_err(|e| TwitchBotError::HelixError(e.to_string()))?;
+
+        credentials
+            .token
+            .ok_or_else(|| TwitchBotError::HelixError("no Twitch token".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use tempfile::TempDir;
+    use twitch_irc::login::{TokenStorage, UserAccessToken};
+    use wiremock::{
+        matchers::{body_json, header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::token_store::TokenStore;
+
+    //The directory is returned so the saved token outlives the setup
+    async fn helix(server: &MockServer) -> (HelixClient, TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = TwitchTokenStorage {
+            token_store: TokenStore::new(dir.path(), None),
+        };
+        storage
+            .update_token(&UserAccessToken {
+                access_token: "access-token".to_string(),
+                refresh_token: "refresh-token".to_string(),
+                created_at: Utc::now(),
+                expires_at: Some(Utc::now() + chrono::Duration::try_hours(4).unwrap()),
+            })
+            .await
+            .unwrap();
+
+        //With the login given up front the credentials never call Twitch
+        let credentials = RefreshingLoginCredentials::init_with_username(
+            Some("bot".to_string()),
+            "client-id".to_string(),
+            "client-secret".to_string(),
+            storage,
+        );
+
+        (
+            HelixClient::new("client-id".to_string(), credentials, server.uri()),
+            dir,
+        )
+    }
+
+    async fn mock_user(server: &MockServer, login: &str, id: &str) {
+        Mock::given(method("GET"))
+            .and(path("/users"))
+            .and(query_param("login", login))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [{ "id": id, "login": login, "display_name": login }]
+            })))
+            .mount(server)
+            .await;
+    }
+
+    //The channel is user 1 and the bot, acting as its moderator, is user 2
+    async fn moderation_server() -> MockServer {
+        let server = MockServer::start().await;
+        mock_user(&server, "canal", "1").await;
+        mock_user(&server, "bot", "2").await;
+        server
+    }
+
+    async fn mock_bans(server: &MockServer, body: Value) {
+        Mock::given(method("POST"))
+            .and(path("/moderation/bans"))
+            .and(query_param("broadcaster_id", "1"))
+            .and(query_param("moderator_id", "2"))
+            .and(header("Authorization", "Bearer access-token"))
+            .and(header("Client-Id", "client-id"))
+            .and(body_json(body))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": [] })))
+            .expect(1)
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn timeout_sends_the_duration_and_reason() {
+        let server = moderation_server().await;
+        mock_bans(
+            &server,
+            json!({ "data": { "user_id": "3", "duration": 600, "reason": "spam" } }),
+        )
+        .await;
+        let (helix, _dir) = helix(&server).await;
+
+        helix
+            .timeout_user_async("Canal", "3", Duration::from_secs(600), Some("spam"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn timeout_is_clamped_to_two_weeks() {
+        let server = moderation_server().await;
+        mock_bans(
+            &server,
+            json!({ "data": { "user_id": "3", "duration": MAX_TIMEOUT_SECONDS } }),
+        )
+        .await;
+        let (helix, _dir) = helix(&server).await;
+
+        helix
+            .timeout_user_async("canal", "3", Duration::from_secs(30 * 24 * 60 * 60), None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn ban_has_no_duration() {
+        let server = moderation_server().await;
+        mock_bans(
+            &server,
+            json!({ "data": { "user_id": "3", "reason": "bot" } }),
+        )
+        .await;
+        let (helix, _dir) = helix(&server).await;
+
+        helix
+            .ban_user_async("canal", "3", Some("bot"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn delete_message_sends_the_message_id() {
+        let server = moderation_server().await;
+        Mock::given(method("DELETE"))
+            .and(path("/moderation/chat"))
+            .and(query_param("broadcaster_id", "1"))
+            .and(query_param("moderator_id", "2"))
+            .and(query_param("message_id", "abc"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let (helix, _dir) = helix(&server).await;
+
+        helix.delete_message_async("canal", "abc").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn chat_settings_only_send_what_changes() {
+        let server = moderation_server().await;
+        Mock::given(method("PATCH"))
+            .and(path("/chat/settings"))
+            .and(query_param("broadcaster_id", "1"))
+            .and(query_param("moderator_id", "2"))
+            .and(header("Authorization", "Bearer access-token"))
+            .and(header("Client-Id", "client-id"))
+            .and(body_json(
+                json!({ "slow_mode": true, "slow_mode_wait_time": 30 }),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": [] })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let (helix, _dir) = helix(&server).await;
+
+        let settings = ChatSettings {
+            slow_mode: Some(true),
+            slow_mode_wait_time: Some(30),
+            ..Default::default()
+        };
+        helix
+            .update_chat_settings_async("canal", &settings)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn followers_and_emote_only_modes() {
+        let server = moderation_server().await;
+        Mock::given(method("PATCH"))
+            .and(path("/chat/settings"))
+            .and(body_json(json!({
+                "follower_mode": true,
+                "follower_mode_duration": 10,
+                "emote_mode": false,
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": [] })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let (helix, _dir) = helix(&server).await;
+
+        let settings = ChatSettings {
+            follower_mode: Some(true),
+            follower_mode_duration: Some(10),
+            emote_mode: Some(false),
+            ..Default::default()
+        };
+        helix
+            .update_chat_settings_async("canal", &settings)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn announcement_defaults_to_the_primary_color() {
+        let server = moderation_server().await;
+        Mock::given(method("POST"))
+            .and(path("/chat/announcements"))
+            .and(query_param("broadcaster_id", "1"))
+            .and(query_param("moderator_id", "2"))
+            .and(body_json(
+                json!({ "message": "Live amanha", "color": "primary" }),
+            ))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/chat/announcements"))
+            .and(body_json(
+                json!({ "message": "Sorteio", "color": "purple" }),
+            ))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let (helix, _dir) = helix(&server).await;
+
+        helix
+            .send_announcement_async("canal", "Live amanha", None)
+            .await
+            .unwrap();
+        helix
+            .send_announcement_async("canal", "Sorteio", Some(AnnouncementColor::Purple))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn user_ids_are_cached() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/users"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [{ "id": "1", "login": "canal", "display_name": "Canal" }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let (helix, _dir) = helix(&server).await;
+
+        assert_eq!(helix.user_id_async("canal").await.unwrap(), "1");
+        assert_eq!(helix.user_id_async("CANAL").await.unwrap(), "1");
+    }
+
+    #[tokio::test]
+    async fn errors_carry_twitch_message_and_status() {
+        let server = moderation_server().await;
+        Mock::given(method("DELETE"))
+            .and(path("/moderation/chat"))
+            .respond_with(ResponseTemplate::new(403).set_body_json(json!({
+                "error": "Forbidden",
+                "status": 403,
+                "message": "The user in moderator_id is not one of the broadcaster's moderators.",
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/moderation/bans"))
+            .respond_with(ResponseTemplate::new(500).set_body_string("oops"))
+            .mount(&server)
+            .await;
+        let (helix, _dir) = helix(&server).await;
+
+        let error = helix
+            .delete_message_async("canal", "abc")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            TwitchBotError::HelixError(message)
+                if message == "The user in moderator_id is not one of the broadcaster's moderators. (403)"
+        ));
+
+        //Without Twitch's JSON the status itself is the message
+        let error = helix.ban_user_async("canal", "3", None).await.unwrap_err();
+        assert!(matches!(
+            error,
+            TwitchBotError::HelixError(message) if message == "500 Internal Server Error (500)"
+        ));
+    }
+
+    #[tokio::test]
+    async fn unknown_channel_is_an_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/users"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": [] })))
+            .mount(&server)
+            .await;
+        let (helix, _dir) = helix(&server).await;
+
+        assert!(matches!(
+            helix.delete_message_async("ninguem", "abc").await,
+            Err(TwitchBotError::HelixError(message)) if message == "no Twitch user named ninguem"
+        ));
+    }
+}
@@ -0,0 +1,318 @@
+use std::{fmt, path::Path};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, Row};
+
+use crate::{error::TwitchBotResult, music::Track};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestOutcome {
+    Queued,
+    Rejected,
+    Skipped,
+}
+
+impl RequestOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RequestOutcome::Queued => "queued",
+            RequestOutcome::Rejected => "rejected",
+            RequestOutcome::Skipped => "skipped",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "queued" => RequestOutcome::Queued,
+            "skipped" => RequestOutcome::Skipped,
+            _ => RequestOutcome::Rejected,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SongRequest {
+    pub user: String,
+    pub track_id: Option<String>,
+    pub track_name: String,
+    pub artist: Option<String>,
+    pub requested_at: DateTime<Utc>,
+    pub outcome: RequestOutcome,
+}
+
+impl SongRequest {
+    pub fn from_track(user: &str, track: &Track, outcome: RequestOutcome) -> Self {
+        Self {
+            user: user.to_string(),
+            track_id: Some(track.id.clone()),
+            track_name: track.name.clone(),
+            artist: track.artist.clone(),
+            requested_at: Utc::now(),
+            outcome,
+        }
+    }
+
+    /// A request that never matched a track, the query is kept as the name
+    pub fn from_query(user: &str, query: &str) -> Self {
+        Self {
+            user: user.to_string(),
+            track_id: None,
+            track_name: query.to_string(),
+            artist: None,
+            requested_at: Utc::now(),
+            outcome: RequestOutcome::Rejected,
+        }
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            user: row.get("user")?,
+            track_id: row.get("track_id")?,
+            track_name: row.get("track_name")?,
+            artist: row.get("artist")?,
+            requested_at: row.get("requested_at")?,
+            outcome: RequestOutcome::parse(&row.get::<_, String>("outcome")?),
+        })
+    }
+}
+
+impl fmt::Display for SongRequest {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.track_name)?;
+
+        if let Some(artist) = &self.artist {
+            fmt.write_str(" - ")?;
+            fmt.write_str(artist)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TopRequest {
+    pub track_name: String,
+    pub artist: Option<String>,
+    pub count: u64,
+}
+
+impl fmt::Display for TopRequest {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.track_name)?;
+
+        if let Some(artist) = &self.artist {
+            fmt.write_str(" - ")?;
+            fmt.write_str(artist)?;
+        }
+
+        write!(fmt, " ({}x)", self.count)
+    }
+}
+
+/// Song requests persisted to a local SQLite database
+#[derive(Debug)]
+pub struct SongHistory {
+    connection: Connection,
+}
+
+impl SongHistory {
+    pub fn open(path: impl AsRef<Path>) -> TwitchBotResult<Self> {
+        let connection = Connection::open(path)?;
+
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS song_requests (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user TEXT NOT NULL,
+                track_id TEXT,
+                track_name TEXT NOT NULL,
+                artist TEXT,
+                requested_at TEXT NOT NULL,
+                outcome TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS song_requests_user ON song_requests (user);",
+        )?;
+
+        Ok(Self { connection })
+    }
+
+    pub fn record(&self, request: &SongRequest) -> TwitchBotResult<()> {
+        self.connection.execute(
+            "INSERT INTO song_requests (user, track_id, track_name, artist, requested_at, outcome)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                request.user.to_lowercase(),
+                request.track_id,
+                request.track_name,
+                request.artist,
+                request.requested_at,
+                request.outcome.as_str(),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Marks the latest queued request for a track as skipped, if chat requested it
+    pub fn mark_skipped(&self, track_id: &str) -> TwitchBotResult<()> {
+        self.connection.execute(
+            "UPDATE song_requests SET outcome = 'skipped' WHERE id = (
+                SELECT id FROM song_requests WHERE track_id = ?1 AND outcome = 'queued'
+                ORDER BY id DESC LIMIT 1
+            )",
+            params![track_id],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn last_requests(&self, limit: u32) -> TwitchBotResult<Vec<SongRequest>> {
+        let mut statement = self.connection.prepare(
+            "SELECT * FROM song_requests WHERE outcome != 'rejected'
+             ORDER BY requested_at DESC, id DESC LIMIT ?1",
+        )?;
+
+        let requests = statement
+            .query_map(params![limit], SongRequest::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(requests)
+    }
+
+    pub fn user_requests(&self, user: &str, limit: u32) -> TwitchBotResult<Vec<SongRequest>> {
+        let mut statement = self.connection.prepare(
+            "SELECT * FROM song_requests WHERE user = ?1 AND outcome != 'rejected'
+             ORDER BY requested_at DESC, id DESC LIMIT ?2",
+        )?;
+
+        let requests = statement
+            .query_map(params![user.to_lowercase(), limit], SongRequest::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(requests)
+    }
+
+    pub fn top_requests(&self, limit: u32) -> TwitchBotResult<Vec<TopRequest>> {
+        let mut statement = self.connection.prepare(
+            "SELECT track_name, artist, COUNT(*) AS count FROM song_requests
+             WHERE track_id IS NOT NULL AND outcome != 'rejected'
+             GROUP BY track_id ORDER BY count DESC, MAX(requested_at) DESC LIMIT ?1",
+        )?;
+
+        let requests = statement
+            .query_map(params![limit], |row| {
+                Ok(TopRequest {
+                    track_name: row.get("track_name")?,
+                    artist: row.get("artist")?,
+                    count: row.get("count")?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(requests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    fn track(id: &str, name: &str) -> Track {
+        Track {
+            id: id.to_string(),
+            name: name.to_string(),
+            artist: Some("Artista".to_string()),
+            duration_ms: 0,
+        }
+    }
+
+    //Requests a minute apart, so the ordering doesn't depend on how fast the test runs
+    fn request(user: &str, track: &Track, outcome: RequestOutcome, minute: i64) -> SongRequest {
+        SongRequest {
+            requested_at: DateTime::UNIX_EPOCH + Duration::try_minutes(minute).unwrap(),
+            ..SongRequest::from_track(user, track, outcome)
+        }
+    }
+
+    fn names(requests: &[SongRequest]) -> Vec<&str> {
+        requests
+            .iter()
+            .map(|request| request.track_name.as_str())
+            .collect()
+    }
+
+    fn history() -> SongHistory {
+        let history = SongHistory::open(":memory:").unwrap();
+        let (a, b, c) = (track("a", "A"), track("b", "B"), track("c", "C"));
+
+        history
+            .record(&request("Ana", &a, RequestOutcome::Queued, 1))
+            .unwrap();
+        history
+            .record(&request("bia", &b, RequestOutcome::Queued, 2))
+            .unwrap();
+        history
+            .record(&request("ana", &a, RequestOutcome::Queued, 3))
+            .unwrap();
+        history
+            .record(&request("ana", &c, RequestOutcome::Rejected, 4))
+            .unwrap();
+        history
+            .record(&SongRequest::from_query("bia", "nao existe"))
+            .unwrap();
+
+        history
+    }
+
+    #[test]
+    fn last_requests_are_newest_first_without_rejected() {
+        let history = history();
+
+        assert_eq!(names(&history.last_requests(10).unwrap()), ["A", "B", "A"]);
+        assert_eq!(names(&history.last_requests(2).unwrap()), ["A", "B"]);
+    }
+
+    #[test]
+    fn user_requests_ignore_the_name_case() {
+        let history = history();
+
+        let requests = history.user_requests("ANA", 10).unwrap();
+        assert_eq!(names(&requests), ["A", "A"]);
+        assert!(requests.iter().all(|request| request.user == "ana"));
+        assert!(history.user_requests("caio", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn top_requests_count_each_track() {
+        let history = history();
+
+        let top: Vec<String> = history
+            .top_requests(10)
+            .unwrap()
+            .iter()
+            .map(|request| request.to_string())
+            .collect();
+        assert_eq!(top, ["A - Artista (2x)", "B - Artista (1x)"]);
+    }
+
+    #[test]
+    fn mark_skipped_only_changes_the_latest_queued_request() {
+        let history = history();
+
+        history.mark_skipped("a").unwrap();
+        //Not a chat request, nothing to mark
+        history.mark_skipped("z").unwrap();
+
+        let outcomes: Vec<RequestOutcome> = history
+            .user_requests("ana", 10)
+            .unwrap()
+            .iter()
+            .map(|request| request.outcome)
+            .collect();
+        assert_eq!(outcomes, [RequestOutcome::Skipped, RequestOutcome::Queued]);
+
+        //Skipped requests still count as played
+        assert_eq!(history.last_requests(10).unwrap().len(), 3);
+    }
+}
@@ -1,7 +1,21 @@
 pub mod browser;
+pub mod chat;
+pub mod cli;
 pub mod commands;
+pub mod config;
 pub mod error;
+pub mod helix;
+pub mod history;
+pub mod moderation;
+pub mod music;
+pub mod outgoing;
 pub mod request_endpoints;
+pub mod scopes;
+pub mod secret;
+pub mod shoutout;
+pub mod simulator;
 pub mod spotify;
+pub mod timers;
+pub mod token_store;
 pub mod twitch_auth;
 pub mod twitch_bot;
@@ -1,13 +1,21 @@
-use std::sync::Arc;
-
-use actix_web::{web, App, HttpServer};
+use clap::Parser;
 use happye_bot::{
     browser,
+    chat::ChatMessage,
+    cli::{AuthService, Cli, CliCommand},
+    commands,
+    config::Config,
     error::{TwitchBotError, TwitchBotResult},
-    request_endpoints::{self, BotAuthState},
-    twitch_bot,
+    history::SongHistory,
+    music::{stub::StubMusicProvider, MusicBackend},
+    request_endpoints::{self, AuthServer},
+    secret::RedactingMakeWriter,
+    simulator::{self, SimulatedSender},
+    spotify::client::{SpotifyAuthFlow, SpotifyClient, SpotifyEndpoints},
+    token_store::{SPOTIFY_TOKEN_FILE, TWITCH_TOKEN_FILE},
+    twitch_auth::{self, TwitchAuthFlow},
+    twitch_bot::{self, BotState},
 };
-use tokio::sync::Mutex;
 use tracing_subscriber::{self, filter, layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
 #[tokio::main]
@@ -15,74 +23,342 @@ pub async fn main() -> TwitchBotResult<()> {
     //Initialize environment variables and tracing
     init_env();
 
-    //Twitch credentials
-    let twitch_id = std::env::var("TWITCH_CLIENT_ID").expect("Twitch client id must be set");
-    let twitch_secret = std::env::var("TWITCH_CLIENT_SECRET").expect("Twitch secret must be set");
-    let spotify_id = std::env::var("SPOTIFY_CLIENT_ID").expect("Spotify client id must be set");
-    let spotify_secret = std::env::var("SPOTIFY_SECRET").expect("Spotify secret must be set");
-    let port = 42069;
-
-    //Arcs
-    let twitch_auth_token = Arc::new(Mutex::new(String::from("")));
-    let spotify_auth_token = Arc::new(Mutex::new(String::from("")));
-
-    //Actix states
-    let bot_auth_state = web::Data::new(BotAuthState {
-        twitch_auth_code: twitch_auth_token.clone(),
-        spotify_auth_code: spotify_auth_token.clone(),
-    });
-
-    //Actix server config
-    let server = HttpServer::new(move || {
-        App::new()
-            .app_data(bot_auth_state.clone())
-            .service(request_endpoints::auth)
-            .service(request_endpoints::spotify_auth)
-    })
-    .bind(("127.0.0.1", port))?
-    .disable_signals()
-    .run();
-
-    //Handle or stopping the server
-    let server_handle = server.handle();
-
-    //Worker tasks
-    let server_task = tokio::spawn(server);
-    let twitch_bot_task = tokio::spawn(twitch_bot::run_async(
-        twitch_id.clone(),
-        twitch_secret,
-        spotify_id.clone(),
-        spotify_secret,
-        port,
-        Arc::clone(&twitch_auth_token),
-        Arc::clone(&spotify_auth_token),
-    ));
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(CliCommand::Run);
 
-    let shutdown = tokio::spawn(async move {
-        tokio::signal::ctrl_c().await?;
-        let server_stop = server_handle.stop(true);
-        server_stop.await;
-        Ok::<(), TwitchBotError>(())
-    });
+    //Offline tools work without credentials, everything else needs a valid config
+    let offline = matches!(
+        command,
+        CliCommand::ListCommands | CliCommand::TestCommand { .. } | CliCommand::Repl { .. }
+    );
 
-    //Open browser to get twitch and spotify token if it doesn't exist locally
-    browser::open_browser_and_authenticate_twitch(twitch_id, port).await;
-    browser::open_browser_and_authenticate_spotify(spotify_id, port).await;
+    //config.toml, or the file passed with --config, overridden by environment variables
+    let config = match offline {
+        true => Config::read(cli.config.as_deref()),
+        false => Config::load(cli.config.as_deref()),
+    };
+    let config = match config.and_then(load_commands_file) {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return Err(e);
+        }
+    };
 
-    //Join all tasks and wait for the shutdown signal
-    tokio::try_join!(server_task, twitch_bot_task, shutdown)
-        .expect("unable to join tasks")
-        .0?;
+    let result = match command {
+        CliCommand::Run => run_bot_async(config).await,
+        CliCommand::Auth { service } => auth_async(config, service).await,
+        CliCommand::CheckConfig => check_config_async(config).await,
+        CliCommand::ListCommands => {
+            list_commands();
+            Ok(())
+        }
+        CliCommand::TestCommand {
+            message,
+            user,
+            is_mod,
+        } => test_command_async(config, message, user, is_mod).await,
+        CliCommand::Repl {
+            user,
+            badges,
+            channel,
+        } => repl_async(config, user, badges, channel).await,
+    };
 
-    Ok(())
+    if let Err(e) = &result {
+        tracing::error!("{}", e);
+    }
+
+    result
 }
 
 fn init_env() {
     dotenvy::dotenv().ok();
 
-    let stdout_log = tracing_subscriber::fmt::layer().pretty();
+    let stdout_log = tracing_subscriber::fmt::layer()
+        .pretty()
+        .with_writer(RedactingMakeWriter(std::io::stdout));
 
     tracing_subscriber::registry()
         .with(stdout_log.with_filter(filter::LevelFilter::INFO))
         .init();
 }
+
+fn load_commands_file(config: Config) -> TwitchBotResult<Config> {
+    if let Some(commands_file) = &config.commands_file {
+        let (commands, timers) = commands::load_commands_file(commands_file)?;
+        tracing::info!(
+            "Loaded {} commands and {} timers from {}",
+            commands,
+            timers,
+            commands_file.display()
+        );
+    }
+
+    Ok(config)
+}
+
+/// Without a Spotify secret the PKCE flow is used, so the secret doesn't need to be shared
+fn spotify_auth_flow(config: &Config) -> SpotifyAuthFlow {
+    match &config.spotify.client_secret {
+        Some(spotify_secret) => SpotifyAuthFlow::ClientSecret(spotify_secret.clone()),
+        None => SpotifyAuthFlow::pkce(),
+    }
+}
+
+async fn run_bot_async(config: Config) -> TwitchBotResult<()> {
+    let spotify_auth_flow = spotify_auth_flow(&config);
+    let token_store = config.token_store();
+
+    //Drop revoked tokens or tokens missing scopes, so the login flows below run again
+    twitch_auth::ensure_valid_token_async(
+        &config.twitch.client_id,
+        &config.twitch_client_secret(),
+        &config.twitch.id_base_url,
+        &token_store,
+        &config.features.twitch_scopes(),
+    )
+    .await?;
+    if config.music.backend == MusicBackend::Spotify {
+        SpotifyClient::ensure_valid_token_async(
+            config.spotify.client_id.clone(),
+            spotify_auth_flow.clone(),
+            token_store.clone(),
+            SpotifyEndpoints::default(),
+            &config.spotify_scopes(),
+        )
+        .await?;
+    }
+
+    let (auth_server, auth_codes) = request_endpoints::start_auth_server(&config)?;
+
+    //Open browser to get twitch and spotify token if it doesn't exist locally
+    if config.twitch.auth_flow == TwitchAuthFlow::Browser
+        && !token_store.exists(TWITCH_TOKEN_FILE).await
+    {
+        open_twitch_login(&config, &auth_server).await;
+    }
+    if config.music.backend == MusicBackend::Spotify
+        && !token_store.exists(SPOTIFY_TOKEN_FILE).await
+    {
+        open_spotify_login(&config, &auth_server, &spotify_auth_flow).await;
+    }
+
+    let twitch_bot_task = tokio::spawn(twitch_bot::run_async(
+        config,
+        spotify_auth_flow,
+        auth_codes,
+        token_store,
+    ));
+
+    //Run until the bot stops, e.g. when a login times out, or the shutdown signal arrives
+    let result = tokio::select! {
+        result = twitch_bot_task => result.expect("unable to join bot task"),
+        result = tokio::signal::ctrl_c() => result.map_err(TwitchBotError::from),
+    };
+
+    auth_server.stop().await?;
+
+    result
+}
+
+/// Logs in to a single service, the saved token is kept until the new login succeeds
+async fn auth_async(config: Config, service: AuthService) -> TwitchBotResult<()> {
+    if service == AuthService::Spotify && config.spotify.client_id.is_empty() {
+        return Err(TwitchBotError::InvalidConfig(
+            "spotify.client_id is required to log in to Spotify (SPOTIFY_CLIENT_ID)".to_string(),
+        ));
+    }
+
+    let token_store = config.token_store();
+    let (auth_server, auth_codes) = request_endpoints::start_auth_server(&config)?;
+
+    let (result, file) = match service {
+        AuthService::Twitch => {
+            if config.twitch.auth_flow == TwitchAuthFlow::Browser {
+                open_twitch_login(&config, &auth_server).await;
+            }
+
+            let result =
+                twitch_auth::login_async(&config, auth_codes.twitch, &token_store, true).await;
+            (result, TWITCH_TOKEN_FILE)
+        }
+        AuthService::Spotify => {
+            let spotify_auth_flow = spotify_auth_flow(&config);
+            open_spotify_login(&config, &auth_server, &spotify_auth_flow).await;
+
+            let result = SpotifyClient::login_async(
+                &config,
+                spotify_auth_flow,
+                auth_codes.spotify,
+                token_store.clone(),
+                true,
+            )
+            .await
+            .map(|_| ());
+            (result, SPOTIFY_TOKEN_FILE)
+        }
+    };
+
+    auth_server.stop().await?;
+    result?;
+
+    tracing::info!("Token saved to {}", token_store.path(file).display());
+    Ok(())
+}
+
+async fn open_twitch_login(config: &Config, auth_server: &AuthServer) {
+    browser::open_browser_and_authenticate_twitch(
+        config.twitch.client_id.clone(),
+        config.server.port,
+        &auth_server.twitch_oauth_state,
+        &config.features.twitch_scopes(),
+    )
+    .await;
+}
+
+async fn open_spotify_login(
+    config: &Config,
+    auth_server: &AuthServer,
+    spotify_auth_flow: &SpotifyAuthFlow,
+) {
+    browser::open_browser_and_authenticate_spotify(
+        config.spotify.client_id.clone(),
+        config.server.port,
+        &auth_server.spotify_oauth_state,
+        spotify_auth_flow.code_challenge(),
+        &config.spotify_scopes(),
+    )
+    .await;
+}
+
+async fn check_config_async(config: Config) -> TwitchBotResult<()> {
+    let token_store = config.token_store();
+    let token_status = |exists: bool| if exists { "saved" } else { "missing" };
+
+    println!("Config OK");
+    println!(
+        "Twitch: channels {}, {:?} login, token {}",
+        config.twitch.channels.join(", "),
+        config.twitch.auth_flow,
+        token_status(token_store.exists(TWITCH_TOKEN_FILE).await)
+    );
+    println!("  scopes: {}", config.features.twitch_scopes().join(" "));
+
+    match config.music.backend {
+        MusicBackend::Spotify => {
+            println!(
+                "Music: Spotify, token {}",
+                token_status(token_store.exists(SPOTIFY_TOKEN_FILE).await)
+            );
+            println!("  scopes: {}", config.spotify_scopes().join(" "));
+        }
+        MusicBackend::Mpd => println!("Music: MPD at {}", config.music.mpd_address),
+    }
+
+    println!("Features: {:?}", config.features);
+    println!(
+        "Auth server: http://{}:{}",
+        config.server.bind_address, config.server.port
+    );
+    println!("Token store: {:?}", token_store);
+    println!("Commands: {}", commands::list_commands().len());
+    println!("Timers: {}", commands::list_timers().len());
+
+    Ok(())
+}
+
+fn list_commands() {
+    for (name, command) in commands::list_commands() {
+        let mut details = Vec::new();
+
+        if command.mod_only {
+            details.push("mod only".to_string());
+        }
+        if command.timeout_seconds > 0 {
+            details.push(format!("{}s cooldown", command.timeout_seconds));
+        }
+
+        let description = if command.usage.is_empty() {
+            &command.response
+        } else {
+            &command.usage
+        };
+
+        if details.is_empty() {
+            println!("{} - {}", name, description);
+        } else {
+            println!("{} ({}) - {}", name, details.join(", "), description);
+        }
+    }
+}
+
+async fn test_command_async(
+    config: Config,
+    message: String,
+    user: String,
+    is_mod: bool,
+) -> TwitchBotResult<()> {
+    //Nothing is saved, the history only lives for this run
+    let history = SongHistory::open(":memory:")?;
+    let state = BotState::new(
+        &config,
+        Box::new(StubMusicProvider::new()),
+        None,
+        history,
+        None,
+    );
+
+    let channel = config
+        .twitch
+        .channels
+        .first()
+        .cloned()
+        .unwrap_or_else(|| "local".to_string());
+    let badges = match is_mod {
+        true => vec!["moderator".to_string()],
+        false => Vec::new(),
+    };
+
+    let sent = simulator::run_once_async(
+        &state,
+        ChatMessage::local(&channel, &user, &badges, &message),
+    )
+    .await;
+
+    if sent.is_empty() {
+        println!("(no response)");
+    }
+    for action in sent {
+        println!("{}", action);
+    }
+
+    Ok(())
+}
+
+async fn repl_async(
+    config: Config,
+    user: String,
+    badges: String,
+    channel: Option<String>,
+) -> TwitchBotResult<()> {
+    let history = SongHistory::open(":memory:")?;
+    let state = BotState::new(
+        &config,
+        Box::new(StubMusicProvider::new()),
+        None,
+        history,
+        None,
+    );
+
+    let channel = channel
+        .or_else(|| config.twitch.channels.first().cloned())
+        .unwrap_or_else(|| "local".to_string());
+
+    let sender = SimulatedSender {
+        name: user,
+        badges: simulator::parse_badges(&badges),
+    };
+
+    simulator::run_async(state, channel, sender).await
+}
@@ -0,0 +1,734 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use regex::Regex;
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::chat::{ChatMessage, ChatSender};
+
+/// Without a scheme or www. a word only counts as a link when it ends in one of these, so a
+/// missing space like "bom dia.tudo bem" isn't caught
+const KNOWN_TLDS: &[&str] = &[
+    "app", "be", "br", "cc", "click", "club", "co", "com", "de", "dev", "gg", "info", "io", "link",
+    "live", "ly", "me", "net", "online", "org", "pt", "ru", "shop", "site", "store", "to", "top",
+    "tv", "uk", "us", "xyz",
+];
+
+/// What a filter does the first time it catches someone, repeat offenses escalate from there
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    Warn,
+    Delete,
+    Timeout,
+}
+
+/// What is actually done to a message after escalation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModerationAction {
+    Warn,
+    Delete,
+    Timeout(Duration),
+}
+
+/// The `[moderation]` section of the config, each filter runs when its section is present
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationConfig {
+    /// A user's offenses are forgotten after this long without a new one
+    pub offense_expiry_minutes: u64,
+    /// Timeouts given as a user keeps offending, the last one repeats
+    pub timeout_seconds: Vec<u64>,
+    pub links: Option<LinkFilter>,
+    pub caps: Option<CapsFilter>,
+    pub symbols: Option<SymbolFilter>,
+    pub repeats: Option<RepeatFilter>,
+    pub banned_phrases: Option<BannedPhraseFilter>,
+}
+
+/// Links to anything outside the allowed domains, unless a mod used !permit
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkFilter {
+    pub action: FilterAction,
+    /// Subdomains are allowed too
+    pub allowed_domains: Vec<String>,
+    /// How long a !permit lasts
+    pub permit_seconds: u64,
+}
+
+/// Messages mostly in capital letters
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapsFilter {
+    pub action: FilterAction,
+    /// Share of the letters that can be uppercase, from 0 to 1
+    pub max_ratio: f64,
+    /// Shorter messages are never caught
+    pub min_length: usize,
+}
+
+/// Messages mostly made of symbols, or with too many emotes
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SymbolFilter {
+    pub action: FilterAction,
+    /// Share of the characters that can be symbols, from 0 to 1
+    pub max_ratio: f64,
+    pub max_emotes: usize,
+    /// Shorter messages are never caught for symbols
+    pub min_length: usize,
+}
+
+/// The same message sent over and over by one user
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepeatFilter {
+    pub action: FilterAction,
+    /// The message that reaches this count is caught
+    pub max_repeats: usize,
+    pub window_seconds: u64,
+}
+
+/// Regular expressions that are never allowed, e.g. "(?i)buy followers"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BannedPhraseFilter {
+    pub action: FilterAction,
+    pub patterns: Vec<String>,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        Self {
+            offense_expiry_minutes: 10,
+            timeout_seconds: vec![60, 600, 3600],
+            links: None,
+            caps: None,
+            symbols: None,
+            repeats: None,
+            banned_phrases: None,
+        }
+    }
+}
+
+impl Default for LinkFilter {
+    fn default() -> Self {
+        Self {
+            action: FilterAction::Delete,
+            allowed_domains: vec!["twitch.tv".to_string()],
+            permit_seconds: 60,
+        }
+    }
+}
+
+impl Default for CapsFilter {
+    fn default() -> Self {
+        Self {
+            action: FilterAction::Warn,
+            max_ratio: 0.7,
+            min_length: 15,
+        }
+    }
+}
+
+impl Default for SymbolFilter {
+    fn default() -> Self {
+        Self {
+            action: FilterAction::Delete,
+            max_ratio: 0.5,
+            max_emotes: 15,
+            min_length: 10,
+        }
+    }
+}
+
+impl Default for RepeatFilter {
+    fn default() -> Self {
+        Self {
+            action: FilterAction::Timeout,
+            max_repeats: 3,
+            window_seconds: 60,
+        }
+    }
+}
+
+impl Default for BannedPhraseFilter {
+    fn default() -> Self {
+        Self {
+            action: FilterAction::Timeout,
+            patterns: Vec::new(),
+        }
+    }
+}
+
+impl ModerationConfig {
+    pub fn has_filters(&self) -> bool {
+        self.links.is_some()
+            || self.caps.is_some()
+            || self.symbols.is_some()
+            || self.repeats.is_some()
+            || self.banned_phrases.is_some()
+    }
+
+    /// Adds every problem with the filters to `problems`
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if self.timeout_seconds.is_empty() || self.timeout_seconds.contains(&0) {
+            problems.push(
+                "moderation.timeout_seconds needs at least one timeout greater than 0".to_string(),
+            );
+        }
+
+        if let Some(caps) = &self.caps {
+            if !(0.0..=1.0).contains(&caps.max_ratio) {
+                problems.push("moderation.caps.max_ratio must be between 0 and 1".to_string());
+            }
+        }
+
+        if let Some(symbols) = &self.symbols {
+            if !(0.0..=1.0).contains(&symbols.max_ratio) {
+                problems.push("moderation.symbols.max_ratio must be between 0 and 1".to_string());
+            }
+        }
+
+        if let Some(repeats) = &self.repeats {
+            if repeats.max_repeats < 2 {
+                problems.push("moderation.repeats.max_repeats must be at least 2".to_string());
+            }
+        }
+
+        if let Some(banned_phrases) = &self.banned_phrases {
+            for pattern in &banned_phrases.patterns {
+                if let Err(e) = Regex::new(pattern) {
+                    problems.push(format!(
+                        "moderation.banned_phrases: invalid pattern {}: {}",
+                        pattern, e
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// A message caught by one of the filters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    pub filter: &'static str,
+    /// Shown to the user in chat
+    pub reason: &'static str,
+    pub action: ModerationAction,
+}
+
+/// Runs the configured filters on every chat message before commands are parsed. Mods and the
+/// broadcaster are never filtered.
+#[derive(Debug)]
+pub struct ModerationFilters {
+    config: ModerationConfig,
+    banned_phrases: Vec<Regex>,
+    link_pattern: Regex,
+    /// Users allowed to post one link, until the permit expires, by channel and login
+    permits: HashMap<(String, String), Instant>,
+    /// Offense count and time of the last offense, by channel and login
+    offenses: HashMap<(String, String), (usize, Instant)>,
+    recent_messages: HashMap<(String, String), VecDeque<(String, Instant)>>,
+}
+
+impl ModerationFilters {
+    pub fn new(config: &ModerationConfig) -> Self {
+        //Patterns are checked by Config::validate, an invalid one is skipped just in case
+        let banned_phrases = config
+            .banned_phrases
+            .iter()
+            .flat_map(|filter| &filter.patterns)
+            .filter_map(|pattern| match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    tracing::warn!("Skipping banned phrase {}: {}", pattern, e);
+                    None
+                }
+            })
+            .collect();
+
+        let link_pattern =
+            Regex::new(r"(?i)\b(https?://)?((?:[a-z0-9-]+\.)+([a-z]{2,}))\b(?:[/:?#]\S*)?")
+                .expect("link pattern is valid");
+
+        Self {
+            config: config.clone(),
+            banned_phrases,
+            link_pattern,
+            permits: HashMap::new(),
+            offenses: HashMap::new(),
+            recent_messages: HashMap::new(),
+        }
+    }
+
+    /// Lets the user post one link in the channel, returns how long the permit lasts or None if
+    /// links aren't filtered
+    pub fn permit(&mut self, channel: &str, user_login: &str) -> Option<Duration> {
+        let links = self.config.links.as_ref()?;
+        let duration = Duration::from_secs(links.permit_seconds);
+
+        self.permits.insert(
+            (channel.to_string(), user_login.to_lowercase()),
+            Instant::now() + duration,
+        );
+
+        Some(duration)
+    }
+
+    pub fn check(&mut self, msg: &ChatMessage) -> Option<Verdict> {
+        if msg.is_mod() {
+            return None;
+        }
+
+        let now = Instant::now();
+        self.prune(now);
+
+        let user = (msg.channel.clone(), msg.sender_login.clone());
+        let (filter, reason, action) = self.matching_filter(msg, &user, now)?;
+        let action = self.escalate(user, action, now);
+
+        Some(Verdict {
+            filter,
+            reason,
+            action,
+        })
+    }
+
+    /// Forgets expired permits, offenses and messages, so users who left don't pile up
+    fn prune(&mut self, now: Instant) {
+        let expiry = self.offense_expiry();
+        let window = self.repeat_window();
+
+        self.permits.retain(|_, expires_at| now < *expires_at);
+        self.offenses
+            .retain(|_, (_, last_offense)| now - *last_offense < expiry);
+        self.recent_messages.retain(|_, recent| {
+            recent.retain(|(_, sent_at)| now - *sent_at < window);
+            !recent.is_empty()
+        });
+    }
+
+    fn offense_expiry(&self) -> Duration {
+        Duration::from_secs(self.config.offense_expiry_minutes * 60)
+    }
+
+    fn repeat_window(&self) -> Duration {
+        let window_seconds = self.config.repeats.as_ref().map_or(0, |r| r.window_seconds);
+        Duration::from_secs(window_seconds)
+    }
+
+    fn matching_filter(
+        &mut self,
+        msg: &ChatMessage,
+        user: &(String, String),
+        now: Instant,
+    ) -> Option<(&'static str, &'static str, FilterAction)> {
+        if let Some(banned_phrases) = &self.config.banned_phrases {
+            if self
+                .banned_phrases
+                .iter()
+                .any(|regex| regex.is_match(&msg.text))
+            {
+                return Some((
+                    "banned phrase",
+                    "essa mensagem nao e permitida",
+                    banned_phrases.action,
+                ));
+            }
+        }
+
+        if let Some(links) = &self.config.links {
+            if self.has_blocked_link(&msg.text, &links.allowed_domains) {
+                //A permit covers a single message
+                let permitted = match self.permits.remove(user) {
+                    Some(expires_at) => now < expires_at,
+                    None => false,
+                };
+
+                if !permitted {
+                    return Some((
+                        "links",
+                        "links nao sao permitidos, peca um !permit para um mod",
+                        links.action,
+                    ));
+                }
+            }
+        }
+
+        if let Some(repeats) = &self.config.repeats {
+            let text = msg.text.trim().to_lowercase();
+
+            //Older messages were dropped by prune
+            let recent = self.recent_messages.entry(user.clone()).or_default();
+            recent.push_back((text.clone(), now));
+
+            let count = recent.iter().filter(|(recent, _)| *recent == text).count();
+            if count >= repeats.max_repeats {
+                return Some((
+                    "repeats",
+                    "pare de repetir a mesma mensagem",
+                    repeats.action,
+                ));
+            }
+        }
+
+        if let Some(caps) = &self.config.caps {
+            let letters: Vec<char> = msg.text.chars().filter(|c| c.is_alphabetic()).collect();
+            let uppercase = letters.iter().filter(|c| c.is_uppercase()).count();
+
+            if msg.text.chars().count() >= caps.min_length
+                && !letters.is_empty()
+                && uppercase as f64 / letters.len() as f64 > caps.max_ratio
+            {
+                return Some(("caps", "menos caps lock, por favor", caps.action));
+            }
+        }
+
+        if let Some(symbols) = &self.config.symbols {
+            let characters: Vec<char> = msg.text.chars().filter(|c| !c.is_whitespace()).collect();
+            let symbol_count = characters.iter().filter(|c| !c.is_alphanumeric()).count();
+
+            let too_many_symbols = characters.len() >= symbols.min_length
+                && symbol_count as f64 / characters.len() as f64 > symbols.max_ratio;
+
+            if too_many_symbols || msg.emote_count > symbols.max_emotes {
+                return Some(("symbols", "sem spam de simbolos ou emotes", symbols.action));
+            }
+        }
+
+        None
+    }
+
+    fn has_blocked_link(&self, text: &str, allowed_domains: &[String]) -> bool {
+        self.link_pattern.captures_iter(text).any(|captures| {
+            let host = captures[2].to_lowercase();
+            let has_scheme = captures.get(1).is_some();
+            let has_known_tld = KNOWN_TLDS.contains(&captures[3].to_lowercase().as_str());
+
+            if !has_scheme && !host.starts_with("www.") && !has_known_tld {
+                return false;
+            }
+
+            let host = host.strip_prefix("www.").unwrap_or(&host);
+
+            !allowed_domains.iter().any(|domain| {
+                let domain = domain.to_lowercase();
+                host == domain || host.ends_with(&format!(".{}", domain))
+            })
+        })
+    }
+
+    /// Each offense within the expiry moves one step up from the filter's action:
+    /// warn, delete, then the configured timeouts
+    fn escalate(
+        &mut self,
+        user: (String, String),
+        action: FilterAction,
+        now: Instant,
+    ) -> ModerationAction {
+        //Expired offenses were dropped by prune
+        let previous_offenses = self.offenses.get(&user).map_or(0, |(count, _)| *count);
+        self.offenses.insert(user, (previous_offenses + 1, now));
+
+        let base_level = match action {
+            FilterAction::Warn => 0,
+            FilterAction::Delete => 1,
+            FilterAction::Timeout => 2,
+        };
+
+        match base_level + previous_offenses {
+            0 => ModerationAction::Warn,
+            1 => ModerationAction::Delete,
+            level => {
+                let timeouts = &self.config.timeout_seconds;
+                let seconds = timeouts
+                    .get(level - 2)
+                    .or(timeouts.last())
+                    .copied()
+                    .unwrap_or(60);
+
+                ModerationAction::Timeout(Duration::from_secs(seconds))
+            }
+        }
+    }
+}
+
+/// Carries out the verdict and tells the user why
+pub async fn apply_verdict_async(chat: &impl ChatSender, msg: &ChatMessage, verdict: Verdict) {
+    tracing::info!(
+        "Filter {} caught {}: {:?}",
+        verdict.filter,
+        msg.sender_name,
+        verdict.action
+    );
+
+    let result = match verdict.action {
+        ModerationAction::Warn => Ok(()),
+        ModerationAction::Delete => chat.delete(msg).await,
+        ModerationAction::Timeout(duration) => {
+            chat.timeout(
+                msg.channel.clone(),
+                msg.sender_id.clone(),
+                duration,
+                Some(verdict.reason.to_string()),
+            )
+            .await
+        }
+    };
+
+    if let Err(e) = result {
+        tracing::warn!("Could not moderate {}: {}", msg.sender_name, e);
+    }
+
+    let warning = match verdict.action {
+        ModerationAction::Timeout(duration) => format!(
+            "@{}, {} (timeout de {}s)",
+            msg.sender_name,
+            verdict.reason,
+            duration.as_secs()
+        ),
+        _ => format!("@{}, {}", msg.sender_name, verdict.reason),
+    };
+
+    if let Err(e) = chat.say(msg.channel.clone(), warning).await {
+        tracing::warn!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(channel: &str, text: &str) -> ChatMessage {
+        ChatMessage::local(channel, "Viewer", &[], text)
+    }
+
+    fn filters(config: ModerationConfig) -> ModerationFilters {
+        ModerationFilters::new(&config)
+    }
+
+    fn links_only() -> ModerationFilters {
+        filters(ModerationConfig {
+            links: Some(LinkFilter::default()),
+            ..Default::default()
+        })
+    }
+
+    fn filter_name(filters: &mut ModerationFilters, text: &str) -> Option<&'static str> {
+        filters
+            .check(&message("canal", text))
+            .map(|verdict| verdict.filter)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn links_need_a_scheme_www_or_a_known_tld() {
+        let mut filters = links_only();
+
+        for text in [
+            "olha https://exemplo.qualquer/pagina",
+            "www.exemplo.qualquer",
+            "compra em loja.com.br",
+            "bit.ly/abc",
+        ] {
+            assert_eq!(filter_name(&mut filters, text), Some("links"), "{}", text);
+        }
+
+        for text in [
+            "bom dia.tudo bem",
+            "versao 1.5 saiu",
+            "ok.obrigado",
+            "https://www.twitch.tv/canal",
+            "clips.twitch.tv/abc",
+        ] {
+            assert_eq!(filter_name(&mut filters, text), None, "{}", text);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn permit_covers_one_link_in_its_channel() {
+        let mut filters = links_only();
+        assert_eq!(
+            filters.permit("canal", "VIEWER"),
+            Some(Duration::from_secs(60))
+        );
+
+        assert!(filters.check(&message("outro", "site.com")).is_some());
+        assert!(filters.check(&message("canal", "site.com")).is_none());
+        assert!(filters.check(&message("canal", "site.com")).is_some());
+
+        filters.permit("canal", "viewer");
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(filters.check(&message("canal", "site.com")).is_some());
+    }
+
+    #[test]
+    fn permit_needs_the_link_filter() {
+        let mut filters = filters(ModerationConfig {
+            caps: Some(CapsFilter::default()),
+            ..Default::default()
+        });
+
+        assert_eq!(filters.permit("canal", "viewer"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mods_are_never_filtered() {
+        let mut filters = links_only();
+        let msg = ChatMessage::local("canal", "Mod", &["moderator".to_string()], "site.com");
+
+        assert!(filters.check(&msg).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_symbols_and_emotes_are_caught() {
+        let mut filters = filters(ModerationConfig {
+            caps: Some(CapsFilter::default()),
+            symbols: Some(SymbolFilter::default()),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            filter_name(&mut filters, "ISSO E MUITO LEGAL"),
+            Some("caps")
+        );
+        assert_eq!(filter_name(&mut filters, "OI CHAT"), None);
+        assert_eq!(filter_name(&mut filters, "!!!!!!??????"), Some("symbols"));
+        assert_eq!(filter_name(&mut filters, "oi chat, tudo bem?"), None);
+
+        let emotes = ChatMessage {
+            emote_count: 16,
+            ..message("canal", "Kappa")
+        };
+        assert_eq!(
+            filters.check(&emotes).map(|verdict| verdict.filter),
+            Some("symbols")
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn banned_phrases_are_caught() {
+        let mut filters = filters(ModerationConfig {
+            banned_phrases: Some(BannedPhraseFilter {
+                patterns: vec!["(?i)buy followers".to_string()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            filter_name(&mut filters, "BUY FOLLOWERS aqui"),
+            Some("banned phrase")
+        );
+        assert_eq!(filter_name(&mut filters, "followers"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeats_are_counted_per_channel_within_the_window() {
+        let mut filters = filters(ModerationConfig {
+            repeats: Some(RepeatFilter::default()),
+            ..Default::default()
+        });
+
+        assert!(filters.check(&message("canal", "oi")).is_none());
+        assert!(filters.check(&message("canal", " OI ")).is_none());
+        assert!(filters.check(&message("outro", "oi")).is_none());
+        assert_eq!(
+            filters
+                .check(&message("canal", "oi"))
+                .map(|verdict| verdict.filter),
+            Some("repeats")
+        );
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(filters.check(&message("canal", "oi")).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeat_offenses_climb_the_ladder() {
+        let mut filters = filters(ModerationConfig {
+            caps: Some(CapsFilter::default()),
+            ..Default::default()
+        });
+        let mut action = |channel| {
+            filters
+                .check(&message(channel, "ISSO E MUITO LEGAL"))
+                .map(|verdict| verdict.action)
+        };
+
+        assert_eq!(action("canal"), Some(ModerationAction::Warn));
+        assert_eq!(action("canal"), Some(ModerationAction::Delete));
+        for seconds in [60, 600, 3600, 3600] {
+            assert_eq!(
+                action("canal"),
+                Some(ModerationAction::Timeout(Duration::from_secs(seconds)))
+            );
+        }
+
+        //Offenses in another channel start over
+        assert_eq!(action("outro"), Some(ModerationAction::Warn));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ladder_starts_at_the_filter_action_and_offenses_expire() {
+        let mut filters = filters(ModerationConfig {
+            links: Some(LinkFilter {
+                action: FilterAction::Timeout,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let mut action = || {
+            filters
+                .check(&message("canal", "site.com"))
+                .map(|verdict| verdict.action)
+        };
+
+        assert_eq!(
+            action(),
+            Some(ModerationAction::Timeout(Duration::from_secs(60)))
+        );
+        assert_eq!(
+            action(),
+            Some(ModerationAction::Timeout(Duration::from_secs(600)))
+        );
+
+        tokio::time::advance(Duration::from_secs(10 * 60)).await;
+        assert_eq!(
+            action(),
+            Some(ModerationAction::Timeout(Duration::from_secs(60)))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_entries_are_pruned() {
+        let mut filters = filters(ModerationConfig {
+            links: Some(LinkFilter::default()),
+            repeats: Some(RepeatFilter::default()),
+            ..Default::default()
+        });
+
+        filters.permit("canal", "outro");
+        filters.check(&message("canal", "site.com"));
+        filters.check(&message("canal", "oi"));
+        assert_eq!(
+            (
+                filters.permits.len(),
+                filters.offenses.len(),
+                filters.recent_messages.len()
+            ),
+            (1, 1, 1)
+        );
+
+        tokio::time::advance(Duration::from_secs(10 * 60)).await;
+        filters.prune(Instant::now());
+
+        assert!(filters.permits.is_empty());
+        assert!(filters.offenses.is_empty());
+        assert!(filters.recent_messages.is_empty());
+    }
+}
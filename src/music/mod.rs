@@ -0,0 +1,87 @@
+pub mod mpd;
+pub mod playlist;
+pub mod stub;
+pub mod youtube;
+
+use std::{fmt, str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use crate::error::{TwitchBotError, TwitchBotResult};
+
+pub type SharedMusicProvider = Arc<Mutex<Box<dyn MusicProvider>>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    /// Backend specific id, a Spotify track id, an MPD file uri or a YouTube video id
+    pub id: String,
+    pub name: String,
+    pub artist: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl fmt::Display for Track {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.name)?;
+
+        if let Some(artist) = &self.artist {
+            fmt.write_str(" - ")?;
+            fmt.write_str(artist)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A player that song requests can be sent to
+#[async_trait]
+pub trait MusicProvider: Send {
+    async fn search(&mut self, query: &str) -> TwitchBotResult<Option<Track>>;
+
+    async fn queue(&mut self, track: &Track) -> TwitchBotResult<()>;
+
+    async fn now_playing(&mut self) -> TwitchBotResult<Option<Track>>;
+
+    async fn skip(&mut self) -> TwitchBotResult<()>;
+
+    /// Tracks queued after the one currently playing
+    async fn upcoming(&mut self) -> TwitchBotResult<Vec<Track>>;
+
+    async fn playlist_tracks(&mut self, _playlist: &str) -> TwitchBotResult<Vec<Track>> {
+        Err(TwitchBotError::UnsupportedByMusicBackend(
+            "reading playlists",
+        ))
+    }
+
+    /// Appends a track to the playlist with the given name, creating it if needed
+    async fn add_to_playlist(&mut self, _playlist: &str, _track: &Track) -> TwitchBotResult<()> {
+        Err(TwitchBotError::UnsupportedByMusicBackend(
+            "saving to playlists",
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MusicBackend {
+    Spotify,
+    /// Plays through an MPD server, see `music.mpd_address`
+    Mpd,
+}
+
+impl FromStr for MusicBackend {
+    type Err = TwitchBotError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "spotify" => Ok(Self::Spotify),
+            "mpd" => Ok(Self::Mpd),
+            _ => Err(TwitchBotError::InvalidConfig(format!(
+                "unknown music backend {}, expected spotify or mpd",
+                value
+            ))),
+        }
+    }
+}
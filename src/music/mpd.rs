@@ -0,0 +1,271 @@
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::error::{TwitchBotError, TwitchBotResult};
+
+use super::{MusicProvider, Track};
+
+/// Music Player Daemon backend, talks the plain text protocol over TCP
+#[derive(Debug, Clone)]
+pub struct MpdClient {
+    pub address: String,
+    pub password: Option<String>,
+}
+
+impl MpdClient {
+    pub async fn connect_async(address: String, password: Option<String>) -> TwitchBotResult<Self> {
+        let client = MpdClient { address, password };
+
+        //Fail early if the server is unreachable or the password is wrong
+        client.command("ping").await?;
+        tracing::info!("Connected to MPD at {}", client.address);
+
+        Ok(client)
+    }
+
+    /// Runs a single command on a fresh connection, since MPD drops idle clients
+    async fn command(&self, command: &str) -> TwitchBotResult<Vec<(String, String)>> {
+        let stream = TcpStream::connect(&self.address).await?;
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        match lines.next_line().await? {
+            Some(greeting) if greeting.starts_with("OK MPD") => (),
+            greeting => {
+                return Err(TwitchBotError::MpdError(format!(
+                    "unexpected greeting {:?}",
+                    greeting
+                )))
+            }
+        }
+
+        if let Some(password) = &self.password {
+            writer
+                .write_all(format!("password {}\n", quote(password)).as_bytes())
+                .await?;
+            read_response(&mut lines).await?;
+        }
+
+        writer
+            .write_all(format!("{}\n", command).as_bytes())
+            .await?;
+        let response = read_response(&mut lines).await?;
+
+        let _ = writer.write_all(b"close\n").await;
+
+        Ok(response)
+    }
+
+    async fn tracks(&self, command: &str) -> TwitchBotResult<Vec<Track>> {
+        Ok(parse_tracks(self.command(command).await?))
+    }
+}
+
+#[async_trait]
+impl MusicProvider for MpdClient {
+    async fn search(&mut self, query: &str) -> TwitchBotResult<Option<Track>> {
+        let tracks = self.tracks(&format!("search any {}", quote(query))).await?;
+
+        Ok(tracks.into_iter().next())
+    }
+
+    async fn queue(&mut self, track: &Track) -> TwitchBotResult<()> {
+        self.command(&format!("add {}", quote(&track.id))).await?;
+
+        //Start playback if the player was idle, otherwise the request just sits there
+        let status = self.command("status").await?;
+        if field(&status, "state") == Some("stop") {
+            self.command("play").await?;
+        }
+
+        Ok(())
+    }
+
+    async fn now_playing(&mut self) -> TwitchBotResult<Option<Track>> {
+        Ok(self.tracks("currentsong").await?.into_iter().next())
+    }
+
+    async fn skip(&mut self) -> TwitchBotResult<()> {
+        self.command("next").await?;
+        Ok(())
+    }
+
+    async fn upcoming(&mut self) -> TwitchBotResult<Vec<Track>> {
+        let status = self.command("status").await?;
+
+        let current = field(&status, "song").and_then(|song| song.parse::<usize>().ok());
+        let queue = self.tracks("playlistinfo").await?;
+
+        Ok(match current {
+            Some(current) => queue.into_iter().skip(current + 1).collect(),
+            None => queue,
+        })
+    }
+
+    async fn playlist_tracks(&mut self, playlist: &str) -> TwitchBotResult<Vec<Track>> {
+        self.tracks(&format!("listplaylistinfo {}", quote(playlist)))
+            .await
+    }
+
+    async fn add_to_playlist(&mut self, playlist: &str, track: &Track) -> TwitchBotResult<()> {
+        self.command(&format!(
+            "playlistadd {} {}",
+            quote(playlist),
+            quote(&track.id)
+        ))
+        .await?;
+
+        Ok(())
+    }
+}
+
+async fn read_response<R>(
+    lines: &mut tokio::io::Lines<BufReader<R>>,
+) -> TwitchBotResult<Vec<(String, String)>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let mut fields = Vec::new();
+
+    while let Some(line) = lines.next_line().await? {
+        if line == "OK" {
+            return Ok(fields);
+        }
+
+        if let Some(error) = line.strip_prefix("ACK ") {
+            return Err(TwitchBotError::MpdError(error.to_string()));
+        }
+
+        if let Some((key, value)) = line.split_once(": ") {
+            fields.push((key.to_string(), value.to_string()));
+        }
+    }
+
+    Err(TwitchBotError::MpdError(
+        "connection closed before OK".to_string(),
+    ))
+}
+
+/// Song lists are flat key/value pairs where every song starts with a `file` key
+fn parse_tracks(fields: Vec<(String, String)>) -> Vec<Track> {
+    let mut tracks: Vec<Track> = Vec::new();
+
+    for (key, value) in fields {
+        match key.as_str() {
+            "file" => tracks.push(Track {
+                name: value.rsplit('/').next().unwrap_or_default().to_string(),
+                id: value,
+                artist: None,
+                duration_ms: 0,
+            }),
+            "Title" => {
+                if let Some(track) = tracks.last_mut() {
+                    track.name = value;
+                }
+            }
+            "Artist" => {
+                if let Some(track) = tracks.last_mut() {
+                    track.artist.get_or_insert(value);
+                }
+            }
+            "duration" => {
+                if let (Some(track), Ok(seconds)) = (tracks.last_mut(), value.parse::<f64>()) {
+                    track.duration_ms = (seconds * 1000.0) as u64;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    tracks
+}
+
+fn field<'a>(fields: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find(|(field_key, _)| field_key == key)
+        .map(|(_, value)| value.as_str())
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn response(text: &str) -> TwitchBotResult<Vec<(String, String)>> {
+        let mut lines = BufReader::new(text.as_bytes()).lines();
+        read_response(&mut lines).await
+    }
+
+    #[tokio::test]
+    async fn read_response_collects_fields_until_ok() {
+        let fields = response("file: a.mp3\nTitle: A: B\nOK\nfile: ignored.mp3\n")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fields,
+            [
+                ("file".to_string(), "a.mp3".to_string()),
+                ("Title".to_string(), "A: B".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn read_response_fails_on_ack_and_on_eof() {
+        match response("ACK [50@0] {add} No such directory\n").await {
+            Err(TwitchBotError::MpdError(error)) => {
+                assert_eq!(error, "[50@0] {add} No such directory")
+            }
+            other => panic!("expected an MPD error, got {:?}", other),
+        }
+
+        assert!(response("file: a.mp3\n").await.is_err());
+    }
+
+    #[test]
+    fn parse_tracks_starts_a_track_at_every_file() {
+        let fields = [
+            ("file", "music/Artist/song one.mp3"),
+            ("Artist", "Artist"),
+            ("Artist", "Featured"),
+            ("Title", "Song One"),
+            ("duration", "201.5"),
+            ("file", "music/untagged.flac"),
+            ("Pos", "1"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+
+        assert_eq!(
+            parse_tracks(fields.to_vec()),
+            [
+                Track {
+                    id: "music/Artist/song one.mp3".to_string(),
+                    name: "Song One".to_string(),
+                    artist: Some("Artist".to_string()),
+                    duration_ms: 201_500,
+                },
+                Track {
+                    id: "music/untagged.flac".to_string(),
+                    name: "untagged.flac".to_string(),
+                    artist: None,
+                    duration_ms: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_tracks_ignores_fields_before_the_first_file() {
+        let fields = vec![("Title".to_string(), "Orphan".to_string())];
+
+        assert!(parse_tracks(fields).is_empty());
+    }
+}
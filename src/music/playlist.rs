@@ -0,0 +1,380 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tokio::{sync::Mutex, time::sleep};
+
+use crate::error::{TwitchBotError, TwitchBotResult};
+
+use super::{MusicProvider, SharedMusicProvider, Track};
+
+const FALLBACK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const REQUESTS_PLAYLIST_NAME: &str = "Chat Requests";
+
+#[derive(Debug, Clone, Default)]
+pub struct PlaylistMode {
+    playlists: HashMap<String, String>,
+    active: Option<String>,
+    tracks: Vec<Track>,
+    next_index: usize,
+}
+
+impl PlaylistMode {
+    pub fn new(playlists: HashMap<String, String>, active: Option<String>) -> Self {
+        let active = active.filter(|name| playlists.contains_key(name));
+
+        Self {
+            playlists,
+            active,
+            tracks: Vec::new(),
+            next_index: 0,
+        }
+    }
+
+    /// Parses `name=playlist,name=playlist` pairs, where playlist is a backend playlist id or a
+    /// Spotify share URL
+    pub fn parse_playlists(value: &str) -> HashMap<String, String> {
+        value
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, playlist)| (name.trim().to_lowercase(), playlist.trim().to_string()))
+            .filter(|(name, playlist)| !name.is_empty() && !playlist.is_empty())
+            .collect()
+    }
+
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.playlists.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        names
+    }
+
+    pub fn set_active(&mut self, name: &str) -> bool {
+        let name = name.to_lowercase();
+
+        if !self.playlists.contains_key(&name) {
+            return false;
+        }
+
+        self.active = Some(name);
+        self.tracks.clear();
+        self.next_index = 0;
+        true
+    }
+
+    /// Name and backend id of the active playlist, copied out so the lock can be released
+    /// while its tracks load
+    pub fn active_playlist(&self) -> Option<(String, String)> {
+        let name = self.active.as_ref()?;
+        let playlist_id = self.playlists.get(name)?;
+
+        Some((name.clone(), playlist_id.clone()))
+    }
+
+    /// Next track of the given playlist, None when its tracks aren't loaded or another playlist
+    /// was picked in the meantime
+    pub fn next_loaded_track(&mut self, name: &str) -> Option<Track> {
+        if self.active.as_deref() != Some(name) || self.tracks.is_empty() {
+            return None;
+        }
+
+        let track = self.tracks[self.next_index % self.tracks.len()].clone();
+        self.next_index = (self.next_index + 1) % self.tracks.len();
+
+        Some(track)
+    }
+
+    /// Keeps the tracks loaded for the given playlist, unless it's no longer the active one
+    pub fn load_tracks(&mut self, name: &str, tracks: Vec<Track>) {
+        if self.active.as_deref() != Some(name) {
+            return;
+        }
+
+        tracing::info!("Loaded {} tracks from playlist {}", tracks.len(), name);
+
+        self.tracks = tracks;
+        self.next_index = 0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RequestsPlaylistMode {
+    /// Every request goes to the same "Chat Requests" playlist
+    Rolling,
+    /// A new playlist is created for every stream, named after the day it started
+    #[serde(alias = "stream")]
+    PerStream,
+}
+
+impl FromStr for RequestsPlaylistMode {
+    type Err = TwitchBotError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "rolling" => Ok(Self::Rolling),
+            "stream" | "per-stream" => Ok(Self::PerStream),
+            _ => Err(TwitchBotError::InvalidConfig(format!(
+                "unknown requests playlist mode {}, expected rolling or stream",
+                value
+            ))),
+        }
+    }
+}
+
+/// Playlist that collects every accepted song request
+#[derive(Debug, Clone)]
+pub struct RequestsPlaylist {
+    mode: RequestsPlaylistMode,
+}
+
+impl RequestsPlaylist {
+    pub fn new(mode: RequestsPlaylistMode) -> Self {
+        Self { mode }
+    }
+
+    pub fn mode(&self) -> RequestsPlaylistMode {
+        self.mode
+    }
+
+    /// Per stream playlists are dated by when the stream started, so a stream that runs past
+    /// midnight keeps one playlist. Today's date when that isn't known, e.g. offline.
+    pub fn name(&self, stream_started_at: Option<DateTime<Utc>>) -> String {
+        match self.mode {
+            RequestsPlaylistMode::Rolling => REQUESTS_PLAYLIST_NAME.to_string(),
+            RequestsPlaylistMode::PerStream => format!(
+                "{} {}",
+                REQUESTS_PLAYLIST_NAME,
+                stream_started_at
+                    .unwrap_or_else(Utc::now)
+                    .format("%Y-%m-%d")
+            ),
+        }
+    }
+
+    pub async fn save_track(
+        &self,
+        music_provider: &mut dyn MusicProvider,
+        stream_started_at: Option<DateTime<Utc>>,
+        track: &Track,
+    ) -> TwitchBotResult<()> {
+        music_provider
+            .add_to_playlist(&self.name(stream_started_at), track)
+            .await
+    }
+}
+
+/// Keeps the player queue from running dry by queueing the active playlist whenever
+/// no chat requests are waiting
+pub async fn run_fallback_async(
+    music_provider: SharedMusicProvider,
+    playlist_mode: Arc<Mutex<PlaylistMode>>,
+) {
+    loop {
+        sleep(FALLBACK_CHECK_INTERVAL).await;
+
+        //Only hold the playlist lock for the copy, !playlist shouldn't wait on the player
+        let Some((name, playlist_id)) = playlist_mode.lock().await.active_playlist() else {
+            continue;
+        };
+
+        //The player is locked for one call at a time, so chat commands can get in between
+        let now_playing = music_provider.lock().await.now_playing().await;
+        let now_playing = match now_playing {
+            Ok(now_playing) => now_playing,
+            Err(e) => {
+                tracing::warn!("Could not read the current track: {}", e);
+                continue;
+            }
+        };
+
+        //Nothing is playing, so there's no device to queue into
+        if now_playing.is_none() {
+            continue;
+        }
+
+        let upcoming = music_provider.lock().await.upcoming().await;
+        match upcoming {
+            Ok(upcoming) if upcoming.is_empty() => (),
+            Ok(_) => continue,
+            Err(e) => {
+                tracing::warn!("Could not read the player queue: {}", e);
+                continue;
+            }
+        }
+
+        let mut track = playlist_mode.lock().await.next_loaded_track(&name);
+
+        if track.is_none() {
+            let tracks = music_provider
+                .lock()
+                .await
+                .playlist_tracks(&playlist_id)
+                .await;
+            let tracks = match tracks {
+                Ok(tracks) => tracks,
+                Err(e) => {
+                    tracing::warn!("Could not load playlist tracks: {}", e);
+                    continue;
+                }
+            };
+
+            let mut playlist_mode = playlist_mode.lock().await;
+            playlist_mode.load_tracks(&name, tracks);
+            track = playlist_mode.next_loaded_track(&name);
+        }
+
+        if let Some(track) = track {
+            tracing::info!("Queue is empty, playing {} from playlist", track);
+            let _ = music_provider.lock().await.queue(&track).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::music::stub::StubMusicProvider;
+
+    fn track(id: &str) -> Track {
+        Track {
+            id: id.to_string(),
+            name: id.to_string(),
+            artist: None,
+            duration_ms: 0,
+        }
+    }
+
+    fn playlist_mode() -> PlaylistMode {
+        let playlists = HashMap::from([
+            ("chill".to_string(), "chill-id".to_string()),
+            ("rock".to_string(), "rock-id".to_string()),
+        ]);
+
+        PlaylistMode::new(playlists, Some("chill".to_string()))
+    }
+
+    #[test]
+    fn loaded_tracks_play_in_a_loop() {
+        let mut mode = playlist_mode();
+        assert_eq!(
+            mode.active_playlist(),
+            Some(("chill".to_string(), "chill-id".to_string()))
+        );
+        assert_eq!(mode.next_loaded_track("chill"), None);
+
+        mode.load_tracks("chill", vec![track("a"), track("b")]);
+
+        assert_eq!(mode.next_loaded_track("chill"), Some(track("a")));
+        assert_eq!(mode.next_loaded_track("chill"), Some(track("b")));
+        assert_eq!(mode.next_loaded_track("chill"), Some(track("a")));
+    }
+
+    #[test]
+    fn playlist_values_are_kept_as_written() {
+        let playlists = PlaylistMode::parse_playlists(
+            "Chill=https://open.spotify.com/playlist/abc?si=x, rock = Rock: 80s/90s ,=x,empty=",
+        );
+
+        assert_eq!(
+            playlists,
+            HashMap::from([
+                (
+                    "chill".to_string(),
+                    "https://open.spotify.com/playlist/abc?si=x".to_string()
+                ),
+                ("rock".to_string(), "Rock: 80s/90s".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn per_stream_playlists_use_the_stream_start_date() {
+        let started_at = DateTime::parse_from_rfc3339("2026-10-18T23:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let rolling = RequestsPlaylist::new(RequestsPlaylistMode::Rolling);
+        assert_eq!(rolling.name(Some(started_at)), "Chat Requests");
+
+        let per_stream = RequestsPlaylist::new(RequestsPlaylistMode::PerStream);
+        assert_eq!(
+            per_stream.name(Some(started_at)),
+            "Chat Requests 2026-10-18"
+        );
+        assert_eq!(
+            per_stream.name(None),
+            format!("Chat Requests {}", Utc::now().format("%Y-%m-%d"))
+        );
+    }
+
+    #[test]
+    fn tracks_of_a_replaced_playlist_are_dropped() {
+        let mut mode = playlist_mode();
+        mode.load_tracks("chill", vec![track("a")]);
+
+        //A mod picked another playlist while the fallback task was loading
+        assert!(mode.set_active("rock"));
+        mode.load_tracks("chill", vec![track("b")]);
+
+        assert_eq!(mode.next_loaded_track("chill"), None);
+        assert_eq!(mode.next_loaded_track("rock"), None);
+    }
+
+    //Starts the fallback task on a stub player with a single playlist
+    fn fallback() -> SharedMusicProvider {
+        let playlists = HashMap::from([("lofi".to_string(), "lofi-id".to_string())]);
+        let playlist_mode = PlaylistMode::new(playlists, Some("lofi".to_string()));
+        let music_provider: SharedMusicProvider =
+            Arc::new(Mutex::new(Box::new(StubMusicProvider::new())));
+
+        tokio::spawn(run_fallback_async(
+            Arc::clone(&music_provider),
+            Arc::new(Mutex::new(playlist_mode)),
+        ));
+
+        music_provider
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fallback_queues_the_playlist_when_the_queue_runs_dry() {
+        let music_provider = fallback();
+        music_provider
+            .lock()
+            .await
+            .queue(&track("playing"))
+            .await
+            .unwrap();
+
+        sleep(FALLBACK_CHECK_INTERVAL + Duration::from_secs(1)).await;
+
+        let upcoming = music_provider.lock().await.upcoming().await.unwrap();
+        assert_eq!(upcoming.len(), 1);
+        assert_eq!(upcoming[0].name, "Track from lofi-id");
+
+        //Something is waiting now, so nothing else is queued
+        sleep(FALLBACK_CHECK_INTERVAL).await;
+        assert_eq!(
+            music_provider.lock().await.upcoming().await.unwrap().len(),
+            1
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fallback_waits_for_something_to_play() {
+        let music_provider = fallback();
+
+        sleep(FALLBACK_CHECK_INTERVAL * 3).await;
+
+        assert!(music_provider
+            .lock()
+            .await
+            .upcoming()
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+
+use async_trait::async_trait;
+
+use crate::error::TwitchBotResult;
+
+use super::{MusicProvider, Track};
+
+/// In memory player for trying commands offline, every search finds a track named after the query
+#[derive(Debug, Clone, Default)]
+pub struct StubMusicProvider {
+    current: Option<Track>,
+    queue: VecDeque<Track>,
+    next_id: u64,
+}
+
+impl StubMusicProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl MusicProvider for StubMusicProvider {
+    async fn search(&mut self, query: &str) -> TwitchBotResult<Option<Track>> {
+        let query = query.trim();
+        if query.is_empty() {
+            return Ok(None);
+        }
+
+        self.next_id += 1;
+
+        //"song - artist" like the real backends display it
+        let (name, artist) = match query.split_once(" - ") {
+            Some((name, artist)) => (name.to_string(), Some(artist.to_string())),
+            None => (query.to_string(), None),
+        };
+
+        Ok(Some(Track {
+            id: format!("stub:{}", self.next_id),
+            name,
+            artist,
+            duration_ms: 180_000,
+        }))
+    }
+
+    async fn queue(&mut self, track: &Track) -> TwitchBotResult<()> {
+        if self.current.is_none() {
+            self.current = Some(track.clone());
+        } else {
+            self.queue.push_back(track.clone());
+        }
+
+        Ok(())
+    }
+
+    async fn now_playing(&mut self) -> TwitchBotResult<Option<Track>> {
+        Ok(self.current.clone())
+    }
+
+    async fn skip(&mut self) -> TwitchBotResult<()> {
+        self.current = self.queue.pop_front();
+        Ok(())
+    }
+
+    async fn upcoming(&mut self) -> TwitchBotResult<Vec<Track>> {
+        Ok(self.queue.iter().cloned().collect())
+    }
+
+    async fn playlist_tracks(&mut self, playlist_id: &str) -> TwitchBotResult<Vec<Track>> {
+        Ok(vec![Track {
+            id: format!("stub:{}", playlist_id),
+            name: format!("Track from {}", playlist_id),
+            artist: None,
+            duration_ms: 180_000,
+        }])
+    }
+
+    async fn add_to_playlist(&mut self, _playlist: &str, _track: &Track) -> TwitchBotResult<()> {
+        Ok(())
+    }
+}
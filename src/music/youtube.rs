@@ -0,0 +1,375 @@
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::Mutex,
+    time::sleep,
+};
+
+use crate::error::{TwitchBotError, TwitchBotResult};
+
+use super::{MusicProvider, Track};
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Plays YouTube links through a local mpv started with `--idle --input-ipc-server=<socket>`.
+/// mpv needs yt-dlp on the PATH to open YouTube URLs.
+#[derive(Debug, Clone)]
+pub struct YoutubePlayer {
+    pub socket_path: String,
+    queue: Arc<Mutex<VecDeque<Track>>>,
+    current: Arc<Mutex<Option<Track>>>,
+}
+
+#[derive(Deserialize)]
+struct YoutubeOEmbed {
+    title: String,
+    author_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MpvMessage {
+    error: Option<String>,
+    data: Option<Value>,
+    event: Option<String>,
+    name: Option<String>,
+}
+
+impl YoutubePlayer {
+    pub async fn connect_async(socket_path: String) -> TwitchBotResult<Self> {
+        //Fail early if mpv isn't listening on the socket
+        mpv_command(&socket_path, json!(["get_property", "mpv-version"])).await?;
+        tracing::info!("Connected to mpv at {}", socket_path);
+
+        let player = YoutubePlayer {
+            socket_path,
+            queue: Arc::new(Mutex::new(VecDeque::new())),
+            current: Arc::new(Mutex::new(None)),
+        };
+
+        tokio::spawn(run_player_async(
+            player.socket_path.clone(),
+            Arc::clone(&player.queue),
+            Arc::clone(&player.current),
+        ));
+
+        Ok(player)
+    }
+}
+
+#[async_trait]
+impl MusicProvider for YoutubePlayer {
+    /// Only resolves YouTube links, there's no keyword search without an API key
+    async fn search(&mut self, query: &str) -> TwitchBotResult<Option<Track>> {
+        let Some(id) = video_id(query) else {
+            return Ok(None);
+        };
+
+        let client = reqwest::Client::new();
+
+        let request = client
+            .get("https://www.youtube.com/oembed")
+            .query(&[("url", video_url(&id)), ("format", "json".to_string())])
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response = request.json::<YoutubeOEmbed>().await?;
+
+        Ok(Some(Track {
+            id,
+            name: response.title,
+            artist: response.author_name,
+            duration_ms: 0,
+        }))
+    }
+
+    async fn queue(&mut self, track: &Track) -> TwitchBotResult<()> {
+        let mut queue = self.queue.lock().await;
+        queue.push_back(track.clone());
+
+        //The player loop only reacts to mpv going idle, so kick it off if it already is
+        let idle = mpv_command(&self.socket_path, json!(["get_property", "idle-active"])).await?;
+        if idle == Value::Bool(true) {
+            play_next(&self.socket_path, &mut queue, &self.current).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn now_playing(&mut self) -> TwitchBotResult<Option<Track>> {
+        Ok(self.current.lock().await.clone())
+    }
+
+    async fn skip(&mut self) -> TwitchBotResult<()> {
+        //Stopping makes mpv idle, which moves the player loop to the next track
+        mpv_command(&self.socket_path, json!(["stop"])).await?;
+        Ok(())
+    }
+
+    async fn upcoming(&mut self) -> TwitchBotResult<Vec<Track>> {
+        Ok(self.queue.lock().await.iter().cloned().collect())
+    }
+}
+
+/// Extracts the video id from youtube.com/watch, youtu.be and shorts links
+pub fn video_id(text: &str) -> Option<String> {
+    let url = text
+        .split_whitespace()
+        .find(|word| word.contains("youtube.com/") || word.contains("youtu.be/"))?;
+
+    let id = if let Some((_, query)) = url.split_once("watch?") {
+        query
+            .split('&')
+            .find_map(|param| param.strip_prefix("v="))?
+    } else {
+        url.rsplit('/').next()?.split(['?', '&', '#']).next()?
+    };
+
+    let valid = id.len() == 11
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    valid.then(|| id.to_string())
+}
+
+fn video_url(id: &str) -> String {
+    format!("https://www.youtube.com/watch?v={}", id)
+}
+
+/// Watches mpv's idle state and feeds it the next queued track whenever it finishes one
+async fn run_player_async(
+    socket_path: String,
+    queue: Arc<Mutex<VecDeque<Track>>>,
+    current: Arc<Mutex<Option<Track>>>,
+) {
+    loop {
+        if let Err(e) = watch_idle(&socket_path, &queue, &current).await {
+            tracing::warn!("Lost connection to mpv: {}", e);
+        }
+
+        sleep(RECONNECT_INTERVAL).await;
+    }
+}
+
+async fn watch_idle(
+    socket_path: &str,
+    queue: &Mutex<VecDeque<Track>>,
+    current: &Mutex<Option<Track>>,
+) -> TwitchBotResult<()> {
+    let stream = connect(socket_path).await?;
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    let observe = json!({ "command": ["observe_property", 1, "idle-active"] });
+    writer
+        .write_all(format!("{}\n", observe).as_bytes())
+        .await?;
+
+    while let Some(line) = lines.next_line().await? {
+        let message: MpvMessage = serde_json::from_str(&line)?;
+
+        let went_idle = message.event.as_deref() == Some("property-change")
+            && message.name.as_deref() == Some("idle-active")
+            && message.data == Some(Value::Bool(true));
+
+        if went_idle {
+            let mut queue = queue.lock().await;
+
+            //A request may have started playback while we waited for the lock
+            let idle = mpv_command(socket_path, json!(["get_property", "idle-active"])).await?;
+            if idle == Value::Bool(true) {
+                play_next(socket_path, &mut queue, current).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn play_next(
+    socket_path: &str,
+    queue: &mut VecDeque<Track>,
+    current: &Mutex<Option<Track>>,
+) -> TwitchBotResult<()> {
+    let next = queue.pop_front();
+
+    if let Some(track) = &next {
+        tracing::info!("Playing {} on mpv", track);
+        mpv_command(
+            socket_path,
+            json!(["loadfile", video_url(&track.id), "replace"]),
+        )
+        .await?;
+    }
+
+    *current.lock().await = next;
+    Ok(())
+}
+
+/// Sends one command over a fresh IPC connection and waits for its reply
+async fn mpv_command(socket_path: &str, command: Value) -> TwitchBotResult<Value> {
+    let stream = connect(socket_path).await?;
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    let request = json!({ "command": command });
+    writer
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+
+    while let Some(line) = lines.next_line().await? {
+        let message: MpvMessage = serde_json::from_str(&line)?;
+
+        //Events are interleaved with command replies
+        if message.event.is_some() {
+            continue;
+        }
+
+        return match message.error.as_deref() {
+            Some("success") => Ok(message.data.unwrap_or(Value::Null)),
+            error => Err(TwitchBotError::MpvError(
+                error.unwrap_or("no reply").to_string(),
+            )),
+        };
+    }
+
+    Err(TwitchBotError::MpvError(
+        "connection closed before reply".to_string(),
+    ))
+}
+
+#[cfg(unix)]
+async fn connect(socket_path: &str) -> std::io::Result<impl AsyncRead + AsyncWrite> {
+    tokio::net::UnixStream::connect(socket_path).await
+}
+
+#[cfg(windows)]
+async fn connect(socket_path: &str) -> std::io::Result<impl AsyncRead + AsyncWrite> {
+    tokio::net::windows::named_pipe::ClientOptions::new().open(socket_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn video_id_reads_every_link_form() {
+        let id = Some("dQw4w9WgXcQ".to_string());
+
+        assert_eq!(video_id("https://www.youtube.com/watch?v=dQw4w9WgXcQ"), id);
+        assert_eq!(
+            video_id("youtube.com/watch?list=PL1&v=dQw4w9WgXcQ&t=42s"),
+            id
+        );
+        assert_eq!(video_id("https://youtu.be/dQw4w9WgXcQ"), id);
+        assert_eq!(video_id("https://youtu.be/dQw4w9WgXcQ?t=42"), id);
+        assert_eq!(video_id("https://youtu.be/dQw4w9WgXcQ#comments"), id);
+        assert_eq!(video_id("https://www.youtube.com/shorts/dQw4w9WgXcQ"), id);
+        assert_eq!(video_id("toca essa https://youtu.be/dQw4w9WgXcQ pfv"), id);
+    }
+
+    #[test]
+    fn video_id_rejects_other_text() {
+        assert_eq!(video_id("never gonna give you up"), None);
+        assert_eq!(video_id("https://youtu.be/short"), None);
+        assert_eq!(video_id("https://www.youtube.com/watch?list=PL1"), None);
+        assert_eq!(video_id("https://vimeo.com/dQw4w9WgXcQ"), None);
+    }
+
+    /// Answers every connection with the given lines and hands back the requests it got
+    #[cfg(unix)]
+    fn fake_mpv(
+        socket_path: &std::path::Path,
+        replies: &'static [&'static str],
+    ) -> tokio::sync::mpsc::UnboundedReceiver<Value> {
+        let listener = tokio::net::UnixListener::bind(socket_path).unwrap();
+        let (requests, received) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (reader, mut writer) = tokio::io::split(stream);
+                let mut lines = BufReader::new(reader).lines();
+
+                if let Ok(Some(line)) = lines.next_line().await {
+                    let _ = requests.send(serde_json::from_str::<Value>(&line).unwrap());
+
+                    for reply in replies {
+                        writer
+                            .write_all(format!("{}\n", reply).as_bytes())
+                            .await
+                            .unwrap();
+                    }
+                }
+            }
+        });
+
+        received
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn mpv_command_skips_events_and_returns_the_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("mpv.sock");
+        let mut requests = fake_mpv(
+            &socket_path,
+            &[
+                r#"{"event":"property-change","name":"idle-active","data":false}"#,
+                r#"{"data":true,"error":"success","request_id":0}"#,
+            ],
+        );
+
+        let data = mpv_command(
+            socket_path.to_str().unwrap(),
+            json!(["get_property", "idle-active"]),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(data, Value::Bool(true));
+        assert_eq!(
+            requests.recv().await.unwrap(),
+            json!({ "command": ["get_property", "idle-active"] })
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn mpv_command_returns_mpv_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("mpv.sock");
+        let _requests = fake_mpv(&socket_path, &[r#"{"error":"property unavailable"}"#]);
+
+        match mpv_command(socket_path.to_str().unwrap(), json!(["get_property", "x"])).await {
+            Err(TwitchBotError::MpvError(error)) => assert_eq!(error, "property unavailable"),
+            other => panic!("expected an mpv error, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn mpv_command_fails_when_mpv_hangs_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("mpv.sock");
+        let _requests = fake_mpv(&socket_path, &[]);
+
+        assert!(mpv_command(socket_path.to_str().unwrap(), json!(["stop"]))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn mpv_command_fails_without_mpv() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("missing.sock");
+
+        assert!(mpv_command(socket_path.to_str().unwrap(), json!(["stop"]))
+            .await
+            .is_err());
+    }
+}
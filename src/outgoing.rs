@@ -0,0 +1,432 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    time::{sleep, Instant},
+};
+
+use crate::{
+    chat::{ChatMessage, ChatSender},
+    error::{TwitchBotError, TwitchBotResult},
+};
+
+/// Twitch allows 20 messages every 30 seconds, or 100 in channels where the bot is a moderator
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(30);
+const USER_RATE_LIMIT: usize = 20;
+const MOD_RATE_LIMIT: usize = 100;
+
+/// Twitch drops a message identical to the previous one sent within 30 seconds
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(30);
+//An invisible tag character, makes the message differ without changing how it looks
+const DUPLICATE_SUFFIX: &str = " \u{E0000}";
+
+pub const MAX_MESSAGE_LENGTH: usize = 500;
+
+/// Channels where the bot is a moderator or the broadcaster, kept up to date from USERSTATE
+#[derive(Debug, Clone, Default)]
+pub struct ModChannels(Arc<Mutex<HashSet<String>>>);
+
+impl ModChannels {
+    pub fn set(&self, channel: &str, is_mod: bool) {
+        let mut channels = self.0.lock().expect("mod channels lock poisoned");
+
+        let changed = if is_mod {
+            channels.insert(channel.to_string())
+        } else {
+            channels.remove(channel)
+        };
+
+        if changed {
+            tracing::info!("Bot is mod in #{}: {}", channel, is_mod);
+        }
+    }
+
+    pub fn is_mod(&self, channel: &str) -> bool {
+        self.0
+            .lock()
+            .expect("mod channels lock poisoned")
+            .contains(channel)
+    }
+}
+
+/// A chat message waiting in the queue
+enum Outgoing {
+    Say {
+        channel: String,
+        message: String,
+    },
+    Me {
+        channel: String,
+        message: String,
+    },
+    Reply {
+        parent: ChatMessage,
+        message: String,
+    },
+}
+
+impl Outgoing {
+    fn channel(&self) -> &str {
+        match self {
+            Self::Say { channel, .. } | Self::Me { channel, .. } => channel,
+            Self::Reply { parent, .. } => &parent.channel,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            Self::Say { message, .. } | Self::Me { message, .. } | Self::Reply { message, .. } => {
+                message
+            }
+        }
+    }
+
+    async fn send(&self, chat: &impl ChatSender, message: String) -> TwitchBotResult<()> {
+        match self {
+            Self::Say { channel, .. } => chat.say(channel.clone(), message).await,
+            Self::Me { channel, .. } => chat.me(channel.clone(), message).await,
+            Self::Reply { parent, .. } => chat.reply(parent, message).await,
+        }
+    }
+}
+
+/// Sends chat messages one at a time within Twitch's rate limits, moderation actions skip the
+/// queue since they go through the API
+pub struct OutgoingQueue<S> {
+    chat: Arc<S>,
+    queue: UnboundedSender<Outgoing>,
+}
+
+//Derived Clone would require S: Clone, only the Arc is cloned
+impl<S> Clone for OutgoingQueue<S> {
+    fn clone(&self) -> Self {
+        Self {
+            chat: Arc::clone(&self.chat),
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<S: ChatSender + 'static> OutgoingQueue<S> {
+    pub fn start(chat: S, mod_channels: ModChannels) -> Self {
+        let chat = Arc::new(chat);
+        let (queue, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(run_queue_async(Arc::clone(&chat), mod_channels, receiver));
+
+        Self { chat, queue }
+    }
+
+    fn push(&self, outgoing: Outgoing) -> TwitchBotResult<()> {
+        self.queue
+            .send(outgoing)
+            .map_err(|_| TwitchBotError::ChatError("the outgoing queue stopped".to_string()))
+    }
+}
+
+#[async_trait]
+impl<S: ChatSender + 'static> ChatSender for OutgoingQueue<S> {
+    async fn say(&self, channel: String, message: String) -> TwitchBotResult<()> {
+        self.push(Outgoing::Say { channel, message })
+    }
+
+    async fn me(&self, channel: String, message: String) -> TwitchBotResult<()> {
+        self.push(Outgoing::Me { channel, message })
+    }
+
+    async fn reply(&self, parent: &ChatMessage, message: String) -> TwitchBotResult<()> {
+        self.push(Outgoing::Reply {
+            parent: parent.clone(),
+            message,
+        })
+    }
+
+    async fn whisper(&self, user: String, message: String) -> TwitchBotResult<()> {
+        self.chat.whisper(user, message).await
+    }
+
+    async fn delete(&self, message: &ChatMessage) -> TwitchBotResult<()> {
+        self.chat.delete(message).await
+    }
+
+    async fn timeout(
+        &self,
+        channel: String,
+        user_id: String,
+        duration: Duration,
+        reason: Option<String>,
+    ) -> TwitchBotResult<()> {
+        self.chat.timeout(channel, user_id, duration, reason).await
+    }
+}
+
+async fn run_queue_async(
+    chat: Arc<impl ChatSender>,
+    mod_channels: ModChannels,
+    mut queue: UnboundedReceiver<Outgoing>,
+) {
+    let mut rate_limiter = RateLimiter::default();
+    let mut last_messages: HashMap<String, (String, Instant)> = HashMap::new();
+
+    while let Some(outgoing) = queue.recv().await {
+        let channel = outgoing.channel().to_string();
+
+        for part in split_message(outgoing.message()) {
+            let limit = match mod_channels.is_mod(&channel) {
+                true => MOD_RATE_LIMIT,
+                false => USER_RATE_LIMIT,
+            };
+
+            if let Some(wait) = rate_limiter.wait_time(limit, Instant::now()) {
+                tracing::info!("Chat rate limit reached, waiting {}s", wait.as_secs());
+                sleep(wait).await;
+            }
+
+            let now = Instant::now();
+            let message = match last_messages.get(&channel) {
+                Some((last, sent_at)) if *last == part && now - *sent_at < DUPLICATE_WINDOW => {
+                    format!("{}{}", part, DUPLICATE_SUFFIX)
+                }
+                _ => part.clone(),
+            };
+
+            rate_limiter.record(now);
+            last_messages.insert(channel.clone(), (message.clone(), now));
+
+            if let Err(e) = outgoing.send(chat.as_ref(), message).await {
+                tracing::warn!("{}", e);
+            }
+        }
+    }
+}
+
+/// Timestamps of the messages sent in the current window
+#[derive(Debug, Default)]
+struct RateLimiter {
+    sent: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    /// How long to wait before another message fits in the window
+    fn wait_time(&mut self, limit: usize, now: Instant) -> Option<Duration> {
+        while self
+            .sent
+            .front()
+            .is_some_and(|sent_at| now - *sent_at >= RATE_LIMIT_WINDOW)
+        {
+            self.sent.pop_front();
+        }
+
+        if self.sent.len() < limit {
+            return None;
+        }
+
+        //The window is full, wait for enough of the oldest messages to fall out of it
+        let oldest = self.sent[self.sent.len() - limit];
+        Some(RATE_LIMIT_WINDOW.saturating_sub(now - oldest))
+    }
+
+    fn record(&mut self, now: Instant) {
+        self.sent.push_back(now);
+    }
+}
+
+/// Splits a message into parts that fit in a Twitch message, breaking at spaces where possible
+pub fn split_message(message: &str) -> Vec<String> {
+    //Leave room for the suffix added to duplicate messages
+    let max_length = MAX_MESSAGE_LENGTH - DUPLICATE_SUFFIX.chars().count();
+
+    let mut parts = Vec::new();
+    let mut rest = message.trim();
+
+    while rest.chars().count() > max_length {
+        let limit = rest
+            .char_indices()
+            .nth(max_length)
+            .map(|(index, _)| index)
+            .unwrap_or(rest.len());
+
+        let split_at = if rest[limit..].starts_with(char::is_whitespace) {
+            limit
+        } else {
+            match rest[..limit].rfind(char::is_whitespace) {
+                Some(index) if index > 0 => index,
+                _ => limit,
+            }
+        };
+
+        parts.push(rest[..split_at].trim_end().to_string());
+        rest = rest[split_at..].trim_start();
+    }
+
+    if !rest.is_empty() {
+        parts.push(rest.to_string());
+    }
+
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{ChatAction, MemoryChat};
+
+    fn say(channel: &str, message: &str) -> Outgoing {
+        Outgoing::Say {
+            channel: channel.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    fn start_queue(mod_channels: ModChannels) -> (MemoryChat, UnboundedSender<Outgoing>) {
+        let chat = MemoryChat::new();
+        let (queue, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(run_queue_async(
+            Arc::new(chat.clone()),
+            mod_channels,
+            receiver,
+        ));
+
+        (chat, queue)
+    }
+
+    fn messages(sent: &[ChatAction]) -> Vec<&str> {
+        sent.iter()
+            .map(|action| match action {
+                ChatAction::Say { message, .. } => message.as_str(),
+                action => panic!("unexpected {:?}", action),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rate_limiter_waits_for_the_oldest_message_to_leave_the_window() {
+        let start = Instant::now();
+        let mut rate_limiter = RateLimiter::default();
+
+        for second in 0..USER_RATE_LIMIT as u64 {
+            let now = start + Duration::from_secs(second);
+            assert_eq!(rate_limiter.wait_time(USER_RATE_LIMIT, now), None);
+            rate_limiter.record(now);
+        }
+
+        let now = start + Duration::from_secs(25);
+        assert_eq!(
+            rate_limiter.wait_time(USER_RATE_LIMIT, now),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(rate_limiter.wait_time(MOD_RATE_LIMIT, now), None);
+
+        //The first message left the window
+        let now = start + Duration::from_secs(30);
+        assert_eq!(rate_limiter.wait_time(USER_RATE_LIMIT, now), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queue_sends_20_messages_per_window() {
+        let (chat, queue) = start_queue(ModChannels::default());
+
+        for i in 0..=USER_RATE_LIMIT {
+            queue
+                .send(say("canal", &format!("mensagem {}", i)))
+                .unwrap();
+        }
+
+        sleep(Duration::from_secs(29)).await;
+        assert_eq!(chat.take_sent().len(), USER_RATE_LIMIT);
+
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(
+            messages(&chat.take_sent()),
+            vec![format!("mensagem {}", USER_RATE_LIMIT)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queue_sends_100_messages_per_window_where_the_bot_is_mod() {
+        let mod_channels = ModChannels::default();
+        mod_channels.set("canal", true);
+        let (chat, queue) = start_queue(mod_channels);
+
+        for i in 0..=MOD_RATE_LIMIT {
+            queue
+                .send(say("canal", &format!("mensagem {}", i)))
+                .unwrap();
+        }
+
+        sleep(Duration::from_secs(29)).await;
+        assert_eq!(chat.take_sent().len(), MOD_RATE_LIMIT);
+
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(chat.take_sent().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeated_message_gets_the_suffix_within_30_seconds() {
+        let (chat, queue) = start_queue(ModChannels::default());
+
+        queue.send(say("canal", "oi")).unwrap();
+        queue.send(say("canal", "oi")).unwrap();
+        queue.send(say("outro", "oi")).unwrap();
+        sleep(Duration::from_secs(1)).await;
+
+        assert_eq!(
+            messages(&chat.take_sent()),
+            vec!["oi", "oi \u{E0000}", "oi"]
+        );
+
+        //Compared with what was actually sent, so a third copy goes out without the suffix
+        queue.send(say("canal", "oi")).unwrap();
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(messages(&chat.take_sent()), vec!["oi"]);
+
+        sleep(DUPLICATE_WINDOW).await;
+        queue.send(say("canal", "oi")).unwrap();
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(messages(&chat.take_sent()), vec!["oi"]);
+    }
+
+    #[test]
+    fn short_message_is_not_split() {
+        assert_eq!(split_message("  oi chat  "), vec!["oi chat"]);
+        assert!(split_message("   ").is_empty());
+    }
+
+    #[test]
+    fn long_message_is_split_on_whitespace() {
+        let words: Vec<String> = (0..150).map(|i| format!("palavra{}", i)).collect();
+        let message = words.join(" ");
+
+        let parts = split_message(&message);
+
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(part.chars().count() + DUPLICATE_SUFFIX.chars().count() <= MAX_MESSAGE_LENGTH);
+            assert!(!part.starts_with(' ') && !part.ends_with(' '));
+        }
+        assert_eq!(parts.join(" "), message);
+    }
+
+    #[test]
+    fn long_multibyte_word_is_split_on_char_boundaries() {
+        let message = "ção".repeat(400);
+
+        let parts = split_message(&message);
+
+        let max_length = MAX_MESSAGE_LENGTH - DUPLICATE_SUFFIX.chars().count();
+        assert_eq!(
+            parts
+                .iter()
+                .map(|part| part.chars().count())
+                .collect::<Vec<_>>(),
+            vec![max_length, max_length, 1200 - 2 * max_length]
+        );
+        assert_eq!(parts.concat(), message);
+    }
+}
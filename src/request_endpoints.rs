@@ -1,37 +1,284 @@
-use std::sync::Arc;
+use std::time::Duration;
 
-use actix_web::{get, web};
-use tokio::sync::Mutex;
+use actix_web::{dev::ServerHandle, get, web, App, HttpResponse, HttpServer};
+use tokio::{
+    sync::{oneshot, Mutex},
+    task::JoinHandle,
+};
 
-use crate::{spotify::models::SpotifyAuthResponse, twitch_auth::TwitchUserAuthResponse};
+use crate::{
+    browser,
+    config::Config,
+    error::{TwitchBotError, TwitchBotResult},
+    secret::{Secret, SecretKind},
+    spotify::models::SpotifyAuthResponse,
+    twitch_auth::TwitchUserAuthResponse,
+};
+
+/// Hands the code from a callback to the task waiting for the login, taken by the first callback
+pub type AuthCodeSender = Mutex<Option<oneshot::Sender<Secret>>>;
 
 pub struct BotAuthState {
-    pub twitch_auth_code: Arc<Mutex<String>>,
-    pub spotify_auth_code: Arc<Mutex<String>>,
+    pub twitch_auth_code: AuthCodeSender,
+    pub spotify_auth_code: AuthCodeSender,
+    /// `state` sent with each authorize URL, callbacks carrying anything else are rejected
+    pub twitch_oauth_state: String,
+    pub spotify_oauth_state: String,
+}
+
+/// The local server receiving the OAuth redirects
+pub struct AuthServer {
+    /// `state` to put in the authorize URLs
+    pub twitch_oauth_state: String,
+    pub spotify_oauth_state: String,
+    handle: ServerHandle,
+    task: JoinHandle<std::io::Result<()>>,
+}
+
+/// Codes received by the auth server, see [`wait_for_auth_code`]
+pub struct AuthCodes {
+    pub twitch: oneshot::Receiver<Secret>,
+    pub spotify: oneshot::Receiver<Secret>,
+}
+
+pub fn start_auth_server(config: &Config) -> TwitchBotResult<(AuthServer, AuthCodes)> {
+    //Auth codes are handed from the callbacks to whoever is waiting for the login
+    let (twitch_auth_sender, twitch_auth_code) = oneshot::channel();
+    let (spotify_auth_sender, spotify_auth_code) = oneshot::channel();
+
+    //OAuth state parameters, one per flow
+    let twitch_oauth_state = browser::generate_oauth_state();
+    let spotify_oauth_state = browser::generate_oauth_state();
+
+    //Actix states
+    let bot_auth_state = web::Data::new(BotAuthState {
+        twitch_auth_code: Mutex::new(Some(twitch_auth_sender)),
+        spotify_auth_code: Mutex::new(Some(spotify_auth_sender)),
+        twitch_oauth_state: twitch_oauth_state.clone(),
+        spotify_oauth_state: spotify_oauth_state.clone(),
+    });
+
+    //Actix server config
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(bot_auth_state.clone())
+            .service(auth)
+            .service(spotify_auth)
+    })
+    .bind((config.server.bind_address.as_str(), config.server.port))?
+    .disable_signals()
+    .run();
+
+    let auth_server = AuthServer {
+        twitch_oauth_state,
+        spotify_oauth_state,
+        handle: server.handle(),
+        task: tokio::spawn(server),
+    };
+
+    let auth_codes = AuthCodes {
+        twitch: twitch_auth_code,
+        spotify: spotify_auth_code,
+    };
+
+    Ok((auth_server, auth_codes))
+}
+
+impl AuthServer {
+    pub async fn stop(self) -> TwitchBotResult<()> {
+        self.handle.stop(true).await;
+        self.task.await.expect("unable to join server task")?;
+        Ok(())
+    }
+}
+
+/// Waits for the code from the local auth callback, giving up if the login isn't finished in time
+pub async fn wait_for_auth_code(
+    receiver: oneshot::Receiver<Secret>,
+    service: &'static str,
+    timeout: Duration,
+) -> TwitchBotResult<Secret> {
+    tracing::info!("Waiting for {} login", service);
+
+    match tokio::time::timeout(timeout, receiver).await {
+        Ok(Ok(code)) => Ok(code),
+        Ok(Err(_)) => Err(TwitchBotError::AuthCallbackClosed(service)),
+        Err(_) => Err(TwitchBotError::AuthTimeout(service)),
+    }
 }
 
 #[get("/auth")]
 async fn auth(
     info: web::Query<TwitchUserAuthResponse>,
     auth_state: web::Data<BotAuthState>,
-) -> String {
-    let mut auth_token = auth_state.twitch_auth_code.lock().await;
-    *auth_token = info.code.clone();
+) -> HttpResponse {
+    //Only a code from the login the bot started is a secret, anything else stays readable
+    if info.state.as_deref() != Some(auth_state.twitch_oauth_state.as_str()) {
+        tracing::warn!("Rejected twitch auth callback with an invalid state");
+        return invalid_state_response();
+    }
+    info.code.register(SecretKind::TwitchAuthCode);
 
-    tracing::info!("Received twitch token {}", info.code.clone());
+    tracing::info!("Received twitch auth code");
 
-    "You can close this now 🎉".into()
+    send_auth_code(&auth_state.twitch_auth_code, info.code.clone()).await
 }
 
 #[get("/spotify-auth")]
 async fn spotify_auth(
     info: web::Query<SpotifyAuthResponse>,
     auth_state: web::Data<BotAuthState>,
-) -> String {
-    let mut auth_token = auth_state.spotify_auth_code.lock().await;
-    *auth_token = info.code.clone();
+) -> HttpResponse {
+    //Only a code from the login the bot started is a secret, anything else stays readable
+    if info.state.as_deref() != Some(auth_state.spotify_oauth_state.as_str()) {
+        tracing::warn!("Rejected spotify auth callback with an invalid state");
+        return invalid_state_response();
+    }
+    info.code.register(SecretKind::SpotifyAuthCode);
+
+    tracing::info!("Received spotify auth code");
+
+    send_auth_code(&auth_state.spotify_auth_code, info.code.clone()).await
+}
+
+async fn send_auth_code(sender: &AuthCodeSender, code: Secret) -> HttpResponse {
+    let sent = match sender.lock().await.take() {
+        Some(sender) => sender.send(code).is_ok(),
+        None => false,
+    };
+
+    if !sent {
+        tracing::warn!("Ignored auth callback, the bot isn't waiting for a login");
+        return HttpResponse::Conflict().body("The bot isn't waiting for this login anymore");
+    }
+
+    HttpResponse::Ok().body("You can close this now 🎉")
+}
+
+fn invalid_state_response() -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type("text/html; charset=utf-8")
+        .body(
+            "<h1>Login failed ❌</h1>\
+             <p>This authorization didn't come from the login the bot started. \
+             Restart the bot to try again.</p>",
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        dev::{Service, ServiceResponse},
+        http::StatusCode,
+        test,
+    };
+
+    use super::*;
+    use crate::secret;
+
+    struct Callbacks {
+        auth_state: web::Data<BotAuthState>,
+        twitch: oneshot::Receiver<Secret>,
+        spotify: oneshot::Receiver<Secret>,
+    }
+
+    fn callbacks() -> Callbacks {
+        let (twitch_auth_sender, twitch) = oneshot::channel();
+        let (spotify_auth_sender, spotify) = oneshot::channel();
+
+        let auth_state = web::Data::new(BotAuthState {
+            twitch_auth_code: Mutex::new(Some(twitch_auth_sender)),
+            spotify_auth_code: Mutex::new(Some(spotify_auth_sender)),
+            twitch_oauth_state: "twitch-state".to_string(),
+            spotify_oauth_state: "spotify-state".to_string(),
+        });
+
+        Callbacks {
+            auth_state,
+            twitch,
+            spotify,
+        }
+    }
+
+    async fn call(auth_state: &web::Data<BotAuthState>, uri: &str) -> StatusCode {
+        let app = test::init_service(
+            App::new()
+                .app_data(auth_state.clone())
+                .service(auth)
+                .service(spotify_auth),
+        )
+        .await;
+
+        let response: ServiceResponse = app
+            .call(test::TestRequest::get().uri(uri).to_request())
+            .await
+            .unwrap();
+        response.status()
+    }
+
+    #[actix_web::test]
+    async fn twitch_callback_needs_the_login_state() {
+        let mut callbacks = callbacks();
+
+        //Codes this short would garble every log line if they were registered
+        let missing = call(&callbacks.auth_state, "/auth?code=m&scope=chat%3Aread").await;
+        let mismatched = call(
+            &callbacks.auth_state,
+            "/auth?code=x&scope=chat%3Aread&state=spotify-state",
+        )
+        .await;
+
+        assert_eq!(missing, StatusCode::BAD_REQUEST);
+        assert_eq!(mismatched, StatusCode::BAD_REQUEST);
+        assert!(callbacks.twitch.try_recv().is_err());
+        assert_eq!(secret::redact("max"), "max");
+
+        let accepted = call(
+            &callbacks.auth_state,
+            "/auth?code=twitch-c0de&scope=chat%3Aread&state=twitch-state",
+        )
+        .await;
+
+        assert_eq!(accepted, StatusCode::OK);
+        assert_eq!(callbacks.twitch.try_recv().unwrap().expose(), "twitch-c0de");
+        assert_eq!(secret::redact("got twitch-c0de"), "got [REDACTED]");
+    }
+
+    #[actix_web::test]
+    async fn spotify_callback_needs_the_login_state() {
+        let mut callbacks = callbacks();
+
+        let missing = call(&callbacks.auth_state, "/spotify-auth?code=w").await;
+        let mismatched = call(
+            &callbacks.auth_state,
+            "/spotify-auth?code=y&state=twitch-state",
+        )
+        .await;
+
+        assert_eq!(missing, StatusCode::BAD_REQUEST);
+        assert_eq!(mismatched, StatusCode::BAD_REQUEST);
+        assert!(callbacks.spotify.try_recv().is_err());
+        assert_eq!(secret::redact("way"), "way");
+
+        let accepted = call(
+            &callbacks.auth_state,
+            "/spotify-auth?code=sp0tify-c0de&state=spotify-state",
+        )
+        .await;
+
+        assert_eq!(accepted, StatusCode::OK);
+        assert_eq!(
+            callbacks.spotify.try_recv().unwrap().expose(),
+            "sp0tify-c0de"
+        );
+    }
 
-    tracing::info!("Received spotify token {}", info.code.clone());
+    #[actix_web::test]
+    async fn only_the_first_code_is_taken() {
+        let callbacks = callbacks();
+        let uri = "/spotify-auth?code=first-c0de&state=spotify-state";
 
-    "You can close this now 🎉".into()
+        assert_eq!(call(&callbacks.auth_state, uri).await, StatusCode::OK);
+        assert_eq!(call(&callbacks.auth_state, uri).await, StatusCode::CONFLICT);
+    }
 }
@@ -0,0 +1,216 @@
+use serde::Deserialize;
+
+/// Always needed to read and answer chat
+const TWITCH_CHAT_SCOPES: [&str; 2] = ["chat:edit", "chat:read"];
+
+/// Deleting messages and timing out users caught by the chat filters, changing the chat
+/// settings and sending announcements
+const TWITCH_MODERATION_SCOPES: [&str; 4] = [
+    "moderator:manage:banned_users",
+    "moderator:manage:chat_messages",
+    "moderator:manage:chat_settings",
+    "moderator:manage:announcements",
+];
+
+/// Changing the stream title and category with !settitle and !setgame
+const TWITCH_STREAM_INFO_SCOPES: [&str; 1] = ["channel:manage:broadcast"];
+
+/// Twitch's own shoutout along with the !so message
+const TWITCH_SHOUTOUT_SCOPES: [&str; 1] = ["moderator:manage:shoutouts"];
+
+/// Queueing, skipping and reading what's playing
+const SPOTIFY_PLAYBACK_SCOPES: [&str; 2] =
+    ["user-modify-playback-state", "user-read-playback-state"];
+
+/// Reading the fallback playlists
+const SPOTIFY_PLAYLIST_READ_SCOPES: [&str; 1] = ["playlist-read-private"];
+
+/// Finding or creating the requests playlist and adding tracks to it
+const SPOTIFY_PLAYLIST_MODIFY_SCOPES: [&str; 3] = [
+    "playlist-read-private",
+    "playlist-modify-public",
+    "playlist-modify-private",
+];
+
+/// Optional modules of the bot, toggled in the `[features]` section of the config. Each one
+/// only asks for the OAuth scopes it uses.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    /// Play tracks from the active playlist when the queue runs out
+    pub fallback_playlists: bool,
+    /// Save accepted song requests to a playlist
+    pub requests_playlist: bool,
+    /// Play YouTube links from song requests through mpv
+    pub youtube_requests: bool,
+    /// Filter links, caps, spam and banned phrases from chat
+    pub moderation: bool,
+    /// Let mods change the title and category, only works when the bot logs in as the channel
+    pub manage_stream_info: bool,
+    /// Send Twitch's own shoutout with !so, the bot must be a mod in the channel
+    pub native_shoutouts: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            fallback_playlists: true,
+            requests_playlist: false,
+            youtube_requests: false,
+            moderation: false,
+            manage_stream_info: false,
+            native_shoutouts: false,
+        }
+    }
+}
+
+impl Features {
+    pub fn twitch_scopes(&self) -> Vec<&'static str> {
+        let mut scopes = TWITCH_CHAT_SCOPES.to_vec();
+
+        if self.moderation {
+            scopes.extend(TWITCH_MODERATION_SCOPES);
+        }
+        if self.manage_stream_info {
+            scopes.extend(TWITCH_STREAM_INFO_SCOPES);
+        }
+        if self.native_shoutouts {
+            scopes.extend(TWITCH_SHOUTOUT_SCOPES);
+        }
+
+        unique(scopes)
+    }
+
+    /// The playlist scope is only asked for when there are fallback playlists to read, so
+    /// tokens from before the feature keep working without them
+    pub fn spotify_scopes(&self, has_playlists: bool) -> Vec<&'static str> {
+        let mut scopes = SPOTIFY_PLAYBACK_SCOPES.to_vec();
+
+        if self.fallback_playlists && has_playlists {
+            scopes.extend(SPOTIFY_PLAYLIST_READ_SCOPES);
+        }
+        if self.requests_playlist {
+            scopes.extend(SPOTIFY_PLAYLIST_MODIFY_SCOPES);
+        }
+
+        unique(scopes)
+    }
+}
+
+/// Required scopes the token wasn't granted, a stored token missing any of them needs a new login
+pub fn missing_scopes<'a>(required: &[&'a str], granted: &[&str]) -> Vec<&'a str> {
+    required
+        .iter()
+        .filter(|scope| !granted.contains(scope))
+        .copied()
+        .collect()
+}
+
+fn unique(scopes: Vec<&'static str>) -> Vec<&'static str> {
+    let mut unique = Vec::with_capacity(scopes.len());
+
+    for scope in scopes {
+        if !unique.contains(&scope) {
+            unique.push(scope);
+        }
+    }
+
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features() -> Features {
+        Features {
+            fallback_playlists: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn chat_scopes_are_always_asked_for() {
+        assert_eq!(features().twitch_scopes(), vec!["chat:edit", "chat:read"]);
+    }
+
+    #[test]
+    fn twitch_features_add_their_scopes() {
+        let scopes = Features {
+            moderation: true,
+            manage_stream_info: true,
+            native_shoutouts: true,
+            ..features()
+        }
+        .twitch_scopes();
+
+        assert_eq!(
+            scopes,
+            vec![
+                "chat:edit",
+                "chat:read",
+                "moderator:manage:banned_users",
+                "moderator:manage:chat_messages",
+                "moderator:manage:chat_settings",
+                "moderator:manage:announcements",
+                "channel:manage:broadcast",
+                "moderator:manage:shoutouts",
+            ]
+        );
+    }
+
+    #[test]
+    fn playlist_scope_needs_configured_playlists() {
+        let playback = vec!["user-modify-playback-state", "user-read-playback-state"];
+        let with_playlists = vec![
+            "user-modify-playback-state",
+            "user-read-playback-state",
+            "playlist-read-private",
+        ];
+
+        assert_eq!(Features::default().spotify_scopes(false), playback);
+        assert_eq!(Features::default().spotify_scopes(true), with_playlists);
+        assert_eq!(features().spotify_scopes(true), playback);
+    }
+
+    #[test]
+    fn requests_playlist_scopes_are_not_repeated() {
+        let scopes = Features {
+            requests_playlist: true,
+            ..Default::default()
+        }
+        .spotify_scopes(true);
+
+        assert_eq!(
+            scopes,
+            vec![
+                "user-modify-playback-state",
+                "user-read-playback-state",
+                "playlist-read-private",
+                "playlist-modify-public",
+                "playlist-modify-private",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_scopes_keep_the_required_order() {
+        let required = ["chat:edit", "chat:read", "moderator:manage:banned_users"];
+
+        assert_eq!(
+            missing_scopes(&required, &["chat:read"]),
+            vec!["chat:edit", "moderator:manage:banned_users"]
+        );
+        assert!(missing_scopes(
+            &required,
+            &[
+                "moderator:manage:banned_users",
+                "chat:read",
+                "chat:edit",
+                "extra"
+            ]
+        )
+        .is_empty());
+        assert!(missing_scopes(&[], &[]).is_empty());
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    sync::RwLock,
+};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing_subscriber::fmt::MakeWriter;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Values kept per kind. A refreshed token replaces the old one, but the old one may still be
+/// accepted for a while, so the last few stay redacted.
+const MAX_SECRETS_PER_KIND: usize = 5;
+
+/// Query/form keys whose values are always redacted, even if the value was never registered
+const SECRET_KEYS: [&str; 5] = [
+    "code=",
+    "access_token=",
+    "refresh_token=",
+    "client_secret=",
+    "code_verifier=",
+];
+
+/// What a secret is used for. The latest few values of each kind are redacted, so refreshed
+/// tokens don't pile up over a long run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SecretKind {
+    TwitchClientSecret,
+    TwitchAuthCode,
+    TwitchDeviceCode,
+    TwitchAccessToken,
+    TwitchRefreshToken,
+    SpotifyClientSecret,
+    SpotifyAuthCode,
+    SpotifyCodeVerifier,
+    SpotifyAccessToken,
+    SpotifyRefreshToken,
+    MpdPassword,
+    TokenEncryptionKey,
+}
+
+lazy_static! {
+    static ref SECRETS: RwLock<HashMap<SecretKind, VecDeque<String>>> = RwLock::new(HashMap::new());
+}
+
+/// Makes sure a value never shows up in tracing output, see [`RedactingMakeWriter`]
+pub fn register(kind: SecretKind, secret: &str) {
+    //An empty value would match everywhere
+    if secret.is_empty() {
+        return;
+    }
+
+    let mut secrets = SECRETS.write().unwrap();
+    let values = secrets.entry(kind).or_default();
+
+    //Registering a value again makes it the newest
+    values.retain(|value| value != secret);
+    values.push_back(secret.to_string());
+
+    if values.len() > MAX_SECRETS_PER_KIND {
+        values.pop_front();
+    }
+}
+
+pub fn redact(text: &str) -> String {
+    let mut redacted = text.to_string();
+
+    for secret in SECRETS.read().unwrap().values().flatten() {
+        redacted = redacted.replace(secret.as_str(), REDACTED);
+    }
+
+    for key in SECRET_KEYS {
+        redacted = redact_key(&redacted, key);
+    }
+
+    redacted
+}
+
+fn redact_key(text: &str, key: &str) -> String {
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(index) = rest.find(key) {
+        let value_start = index + key.len();
+
+        //Only whole parameters, error_code= isn't a code
+        let longer_key = rest[..index]
+            .chars()
+            .next_back()
+            .or_else(|| redacted.chars().next_back())
+            .is_some_and(|c| c.is_alphanumeric() || c == '_');
+        if longer_key {
+            redacted.push_str(&rest[..value_start]);
+            rest = &rest[value_start..];
+            continue;
+        }
+
+        let value_end = rest[value_start..]
+            .find(|c: char| c == '&' || c == '"' || c == '\'' || c.is_whitespace())
+            .map_or(rest.len(), |end| value_start + end);
+
+        redacted.push_str(&rest[..value_start]);
+
+        //Keep the key but hide the value, unless it was already redacted
+        let value = &rest[value_start..value_end];
+        if value.is_empty() || value == REDACTED {
+            redacted.push_str(value);
+        } else {
+            redacted.push_str(REDACTED);
+        }
+
+        rest = &rest[value_end..];
+    }
+
+    redacted.push_str(rest);
+    redacted
+}
+
+/// A token, code or client secret. `Debug` never prints the value, and once it's registered
+/// under its kind it also can't leak through the log output. Values read from config files and
+/// API responses are registered where they're parsed, since only the caller knows the kind.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(kind: SecretKind, value: String) -> Self {
+        register(kind, &value);
+        Self(value)
+    }
+
+    pub fn register(&self, kind: SecretKind) {
+        register(kind, &self.0);
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self(String::deserialize(deserializer)?))
+    }
+}
+
+/// Wraps the tracing output and scrubs secrets from every formatted event
+pub struct RedactingMakeWriter<M>(pub M);
+
+pub struct RedactingWriter<W>(W);
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingMakeWriter<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(self.0.make_writer())
+    }
+}
+
+impl<W: io::Write> io::Write for RedactingWriter<W> {
+    //tracing-subscriber writes each event in a single call, so secrets are never split
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    //The registry is global and tests run in parallel, so every test uses its own kinds
+
+    #[test]
+    fn registered_secrets_never_reach_the_log_output() {
+        #[derive(Clone, Default)]
+        struct Output(Arc<Mutex<Vec<u8>>>);
+
+        impl io::Write for Output {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let output = Output::default();
+        let make_writer = {
+            let output = output.clone();
+            RedactingMakeWriter(move || output.clone())
+        };
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(make_writer)
+            .with_ansi(false)
+            .finish();
+
+        let token = Secret::new(SecretKind::TwitchDeviceCode, "d3v1c3-c0d3".to_string());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("Polling with {} and {:?}", token.expose(), token);
+        });
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("Polling with [REDACTED] and [REDACTED]"));
+        assert!(!output.contains("d3v1c3-c0d3"));
+    }
+
+    #[test]
+    fn previous_values_of_a_kind_stay_redacted() {
+        register(SecretKind::TwitchClientSecret, "old-twitch-secret");
+        register(SecretKind::TwitchClientSecret, "new-twitch-secret");
+
+        assert_eq!(
+            redact("old-twitch-secret new-twitch-secret"),
+            "[REDACTED] [REDACTED]"
+        );
+    }
+
+    #[test]
+    fn only_the_last_few_values_of_a_kind_are_kept() {
+        register(SecretKind::SpotifyClientSecret, "secret-0");
+        for i in 1..=MAX_SECRETS_PER_KIND {
+            //Registering the first value again keeps it from being the oldest
+            register(SecretKind::SpotifyClientSecret, "secret-0");
+            register(
+                SecretKind::SpotifyClientSecret,
+                &format!("secret-{}", i + 10),
+            );
+        }
+        register(SecretKind::SpotifyClientSecret, "secret-99");
+
+        assert_eq!(
+            SECRETS.read().unwrap()[&SecretKind::SpotifyClientSecret].len(),
+            MAX_SECRETS_PER_KIND
+        );
+        assert_eq!(redact("secret-0"), "[REDACTED]");
+        assert_eq!(redact("secret-11"), "secret-11");
+        assert_eq!(redact("secret-99"), "[REDACTED]");
+    }
+
+    #[test]
+    fn short_secrets_are_redacted_and_empty_ones_ignored() {
+        register(SecretKind::MpdPassword, "hunter");
+        register(SecretKind::TokenEncryptionKey, "");
+
+        assert_eq!(redact("mpd password hunter"), "mpd password [REDACTED]");
+        assert_eq!(redact("nothing here"), "nothing here");
+    }
+
+    #[test]
+    fn secret_keys_are_redacted_as_whole_parameters() {
+        assert_eq!(
+            redact("GET /auth?code=abc123&scope=chat%3Aread&state=xyz"),
+            "GET /auth?code=[REDACTED]&scope=chat%3Aread&state=xyz"
+        );
+        assert_eq!(
+            redact("grant_type=refresh_token&refresh_token=r3fr3sh"),
+            "grant_type=refresh_token&refresh_token=[REDACTED]"
+        );
+        assert_eq!(
+            redact("code=abc123 error_code=429 qrcode=x"),
+            "code=[REDACTED] error_code=429 qrcode=x"
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use chrono::Utc;
+use tokio::time::Instant;
+
+use crate::{error::TwitchBotResult, helix::HelixClient};
+
+/// Twitch allows one native shoutout every 2 minutes per channel, and one every hour for the
+/// same streamer
+const SHOUTOUT_COOLDOWN: Duration = Duration::from_secs(2 * 60);
+const SAME_TARGET_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+
+/// Only clips from the last month are suggested
+const CLIP_MAX_AGE_DAYS: i64 = 30;
+
+/// Tracks the native shoutout cooldowns, so the bot doesn't call Twitch when it would refuse.
+/// Only the cooldowns are locked, never while waiting on Twitch.
+#[derive(Debug, Default)]
+pub struct Shoutouts {
+    /// Also send Twitch's own shoutout, when features.native_shoutouts is on
+    native: bool,
+    cooldowns: Mutex<Cooldowns>,
+}
+
+/// When the last native shoutout went out in each channel, and to each target per channel
+#[derive(Debug, Default)]
+struct Cooldowns {
+    last_sent: HashMap<String, Instant>,
+    last_target: HashMap<(String, String), Instant>,
+}
+
+impl Cooldowns {
+    /// Starts both cooldowns, or returns false if either is still running
+    fn start(&mut self, channel: &str, target_login: &str, now: Instant) -> bool {
+        let target_key = (channel.to_string(), target_login.to_string());
+
+        let channel_cooling_down = self
+            .last_sent
+            .get(channel)
+            .is_some_and(|sent_at| now - *sent_at < SHOUTOUT_COOLDOWN);
+        let target_cooling_down = self
+            .last_target
+            .get(&target_key)
+            .is_some_and(|sent_at| now - *sent_at < SAME_TARGET_COOLDOWN);
+
+        if channel_cooling_down || target_cooling_down {
+            return false;
+        }
+
+        self.last_sent.insert(channel.to_string(), now);
+        self.last_target.insert(target_key, now);
+        true
+    }
+
+    /// Gives back the cooldowns of a shoutout Twitch refused. Any earlier entries had already
+    /// expired, otherwise `start` would have refused.
+    fn cancel(&mut self, channel: &str, target_login: &str) {
+        self.last_sent.remove(channel);
+        self.last_target
+            .remove(&(channel.to_string(), target_login.to_string()));
+    }
+}
+
+impl Shoutouts {
+    pub fn new(native: bool) -> Self {
+        Self {
+            native,
+            ..Default::default()
+        }
+    }
+
+    /// Fills in the target's name, channel, last category and a recent clip. None when there's
+    /// no Twitch user with that name.
+    pub async fn shoutout_async(
+        &self,
+        helix: &HelixClient,
+        channel: &str,
+        target: &str,
+        response: &str,
+    ) -> TwitchBotResult<Option<String>> {
+        let target = target.trim_start_matches('@').to_lowercase();
+
+        let Some(user) = helix.get_user_async(&target).await? else {
+            return Ok(None);
+        };
+
+        let game = helix
+            .get_channel_async(&user.login)
+            .await?
+            .map(|info| info.game_name)
+            .filter(|game| !game.is_empty())
+            .unwrap_or_else(|| "nada ainda".to_string());
+
+        let started_at = Utc::now() - chrono::Duration::try_days(CLIP_MAX_AGE_DAYS).unwrap();
+        let clip = match helix.get_top_clip_async(&user.id, started_at).await {
+            Ok(Some(clip)) => format!(" Clipe: {}", clip.url),
+            Ok(None) => "".to_string(),
+            Err(e) => {
+                tracing::warn!("Could not look up clips of {}: {}", user.login, e);
+                "".to_string()
+            }
+        };
+
+        if self.native {
+            self.send_native_async(helix, channel, &user.login, &user.id)
+                .await;
+        }
+
+        Ok(Some(
+            response
+                .replace("<user>", &user.display_name)
+                .replace("<login>", &user.login)
+                .replace("<game>", &game)
+                .replace("<clip>", &clip),
+        ))
+    }
+
+    async fn send_native_async(
+        &self,
+        helix: &HelixClient,
+        channel: &str,
+        target_login: &str,
+        target_id: &str,
+    ) {
+        //Started before calling Twitch, so two !so at once can't both go through
+        let started = self
+            .cooldowns
+            .lock()
+            .expect("shoutout cooldowns lock poisoned")
+            .start(channel, target_login, Instant::now());
+
+        if !started {
+            tracing::info!(
+                "Skipping the native shoutout to {} in #{}, still on cooldown",
+                target_login,
+                channel
+            );
+            return;
+        }
+
+        //Twitch also refuses shoutouts while the channel is offline, that's only logged
+        if let Err(e) = helix.send_shoutout_async(channel, target_id).await {
+            tracing::warn!(
+                "Could not send the native shoutout to {}: {}",
+                target_login,
+                e
+            );
+
+            self.cooldowns
+                .lock()
+                .expect("shoutout cooldowns lock poisoned")
+                .cancel(channel, target_login);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn one_shoutout_per_channel_every_2_minutes() {
+        let mut cooldowns = Cooldowns::default();
+
+        assert!(cooldowns.start("canal", "ana", Instant::now()));
+        assert!(!cooldowns.start("canal", "bia", Instant::now()));
+        assert!(cooldowns.start("outro", "bia", Instant::now()));
+
+        tokio::time::advance(SHOUTOUT_COOLDOWN - Duration::from_secs(1)).await;
+        assert!(!cooldowns.start("canal", "bia", Instant::now()));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(cooldowns.start("canal", "bia", Instant::now()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn one_shoutout_per_target_every_hour() {
+        let mut cooldowns = Cooldowns::default();
+
+        assert!(cooldowns.start("canal", "ana", Instant::now()));
+
+        tokio::time::advance(SHOUTOUT_COOLDOWN).await;
+        assert!(!cooldowns.start("canal", "ana", Instant::now()));
+        //The same target in another channel is a separate shoutout
+        assert!(cooldowns.start("outro", "ana", Instant::now()));
+
+        tokio::time::advance(SAME_TARGET_COOLDOWN - SHOUTOUT_COOLDOWN).await;
+        assert!(cooldowns.start("canal", "ana", Instant::now()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refused_shoutout_gives_back_the_cooldowns() {
+        let mut cooldowns = Cooldowns::default();
+
+        assert!(cooldowns.start("canal", "ana", Instant::now()));
+        cooldowns.cancel("canal", "ana");
+
+        assert!(cooldowns.start("canal", "ana", Instant::now()));
+    }
+}
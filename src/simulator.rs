@@ -0,0 +1,121 @@
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader, Lines, Stdin},
+    sync::mpsc,
+};
+
+use crate::{
+    chat::{ChatAction, ChatMessage, ChatReceiver, ConsoleChat, MemoryChat},
+    error::TwitchBotResult,
+    twitch_bot::{self, BotState},
+};
+
+const HELP: &str = "Type chat messages, or:
+  /user <name>          send as another user
+  /badges <a,b,...>     set the sender's badges, e.g. moderator or broadcaster,subscriber
+  /help                 show this again
+  /quit                 leave";
+
+/// Who the typed lines are sent as
+#[derive(Debug, Clone)]
+pub struct SimulatedSender {
+    pub name: String,
+    pub badges: Vec<String>,
+}
+
+/// Turns lines typed on stdin into chat messages, handling the /commands on the way
+pub struct ConsoleReceiver {
+    lines: Lines<BufReader<Stdin>>,
+    channel: String,
+    sender: SimulatedSender,
+}
+
+impl ConsoleReceiver {
+    pub fn new(channel: String, sender: SimulatedSender) -> Self {
+        Self {
+            lines: BufReader::new(tokio::io::stdin()).lines(),
+            channel,
+            sender,
+        }
+    }
+}
+
+#[async_trait]
+impl ChatReceiver for ConsoleReceiver {
+    async fn recv(&mut self) -> Option<ChatMessage> {
+        loop {
+            let line = match self.lines.next_line().await {
+                Ok(line) => line?,
+                Err(e) => {
+                    tracing::warn!("Could not read from stdin: {}", e);
+                    return None;
+                }
+            };
+            let line = line.trim();
+
+            if let Some(name) = line.strip_prefix("/user ") {
+                self.sender.name = name.trim().to_string();
+                println!("Sending as {}", self.sender.name);
+                continue;
+            }
+
+            if let Some(badges) = line.strip_prefix("/badges") {
+                self.sender.badges = parse_badges(badges);
+                println!("Badges: {}", self.sender.badges.join(", "));
+                continue;
+            }
+
+            match line {
+                "" => continue,
+                "/quit" => return None,
+                "/help" => {
+                    println!("{}", HELP);
+                    continue;
+                }
+                _ => (),
+            }
+
+            return Some(ChatMessage::local(
+                &self.channel,
+                &self.sender.name,
+                &self.sender.badges,
+                line,
+            ));
+        }
+    }
+}
+
+/// Reads chat lines from stdin and answers on stdout, going through the same message loop
+/// as a live channel
+pub async fn run_async(
+    state: BotState,
+    channel: String,
+    sender: SimulatedSender,
+) -> TwitchBotResult<()> {
+    println!("{}", HELP);
+
+    let mut incoming_messages = ConsoleReceiver::new(channel, sender);
+    twitch_bot::handle_messages_async(&ConsoleChat, &mut incoming_messages, &state).await;
+
+    Ok(())
+}
+
+/// Runs a single message through the message loop and returns everything the bot sent
+pub async fn run_once_async(state: &BotState, message: ChatMessage) -> Vec<ChatAction> {
+    let chat = MemoryChat::new();
+    let (sender, mut incoming_messages) = mpsc::unbounded_channel();
+    let _ = sender.send(message);
+    drop(sender);
+
+    twitch_bot::handle_messages_async(&chat, &mut incoming_messages, state).await;
+
+    chat.take_sent()
+}
+
+pub fn parse_badges(badges: &str) -> Vec<String> {
+    badges
+        .split(',')
+        .map(|badge| badge.trim().to_lowercase())
+        .filter(|badge| !badge.is_empty())
+        .collect()
+}
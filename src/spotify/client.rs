@@ -1,40 +1,135 @@
+use async_trait::async_trait;
 use base64::Engine;
 use chrono::{Duration, Utc};
-use tokio::io::AsyncReadExt;
+use rand::{distributions::Uniform, Rng};
+use sha2::{Digest, Sha256};
+use tokio::sync::oneshot;
+
+use crate::{
+    config::Config,
+    error::{TwitchBotError, TwitchBotResult},
+    music::{MusicProvider, Track},
+    request_endpoints::wait_for_auth_code,
+    scopes,
+    secret::{Secret, SecretKind},
+    token_store::{TokenStore, SPOTIFY_TOKEN_FILE},
+};
+use std::{collections::HashMap, fmt};
+
+use super::models::{
+    SpotifyPlaylist, SpotifyPlaylistTracksPage, SpotifyPlaylistsPage, SpotifyQueue,
+    SpotifySearchResult, SpotifyToken, SpotifyTrack, SpotifyTrackResults, SpotifyUser,
+};
+
+pub const SPOTIFY_ACCOUNTS_BASE_URL: &str = "https://accounts.spotify.com";
+pub const SPOTIFY_API_BASE_URL: &str = "https://api.spotify.com";
+
+/// Where the accounts service and the Web API live, overridable to point at a mock server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpotifyEndpoints {
+    pub accounts_base_url: String,
+    pub api_base_url: String,
+}
+
+impl Default for SpotifyEndpoints {
+    fn default() -> Self {
+        Self {
+            accounts_base_url: SPOTIFY_ACCOUNTS_BASE_URL.to_string(),
+            api_base_url: SPOTIFY_API_BASE_URL.to_string(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum SpotifyAuthFlow {
+    /// Authorization code flow using the app's client secret
+    ClientSecret(Secret),
+    /// Authorization code flow with PKCE, so no secret has to be shipped with the bot
+    Pkce { code_verifier: Secret },
+}
 
-use crate::error::TwitchBotResult;
-use std::collections::HashMap;
+impl SpotifyAuthFlow {
+    pub fn pkce() -> Self {
+        //RFC 7636 allows 43 to 128 characters from this set
+        let charset = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-._~";
+        let code_verifier: String = rand::thread_rng()
+            .sample_iter(Uniform::from(0..charset.len()))
+            .take(64)
+            .map(|i| charset[i] as char)
+            .collect();
+
+        Self::Pkce {
+            code_verifier: Secret::new(SecretKind::SpotifyCodeVerifier, code_verifier),
+        }
+    }
+
+    /// The S256 challenge sent with the authorize URL, None for the client secret flow
+    pub fn code_challenge(&self) -> Option<String> {
+        match self {
+            Self::ClientSecret(_) => None,
+            Self::Pkce { code_verifier } => Some(
+                base64::engine::general_purpose::URL_SAFE_NO_PAD
+                    .encode(Sha256::digest(code_verifier.expose().as_bytes())),
+            ),
+        }
+    }
+}
 
-use super::models::{SpotifySearchResult, SpotifyToken, SpotifyTrack, SpotifyTrackResults};
+//Written by hand so the client secret and the code verifier never end up in the logs
+impl fmt::Debug for SpotifyAuthFlow {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::ClientSecret(_) => fmt.write_str("ClientSecret(..)"),
+            Self::Pkce { .. } => fmt.write_str("Pkce { .. }"),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct SpotifyClient {
     pub client_id: String,
-    pub client_secret: String,
+    pub auth_flow: SpotifyAuthFlow,
     pub token: Option<SpotifyToken>,
+    /// Playlist ids by name, filled as playlists are looked up or created
+    pub playlist_ids: HashMap<String, String>,
+    pub token_store: TokenStore,
+    pub endpoints: SpotifyEndpoints,
 }
 
 impl SpotifyClient {
+    /// A client with the saved token, None when there's no token file
+    pub async fn load_async(
+        client_id: String,
+        auth_flow: SpotifyAuthFlow,
+        token_store: TokenStore,
+        endpoints: SpotifyEndpoints,
+    ) -> TwitchBotResult<Option<Self>> {
+        let Some(token) = token_store.load::<SpotifyToken>(SPOTIFY_TOKEN_FILE).await? else {
+            return Ok(None);
+        };
+        token.register_secrets();
+
+        Ok(Some(SpotifyClient {
+            client_id,
+            auth_flow,
+            token: Some(token),
+            playlist_ids: HashMap::new(),
+            token_store,
+            endpoints,
+        }))
+    }
+
+    /// Exchanges the code from the browser login for a token and saves it, replacing any
+    /// saved token
     pub async fn create_async(
         client_id: String,
-        client_secret: String,
+        auth_flow: SpotifyAuthFlow,
         auth_token: String,
         port: u16,
+        token_store: TokenStore,
+        endpoints: SpotifyEndpoints,
     ) -> TwitchBotResult<Self> {
-        //Get token from file, if it doens't exist, make request
-        if let Ok(mut token_file) = tokio::fs::File::open("spotify_token.json").await {
-            let mut contents = String::new();
-            token_file.read_to_string(&mut contents).await?;
-            let token: SpotifyToken = serde_json::from_str(&contents)?;
-
-            return Ok(SpotifyClient {
-                client_id,
-                client_secret,
-                token: Some(token),
-            });
-        }
-
-        let url = "https://accounts.spotify.com/api/token";
+        let url = format!("{}/api/token", endpoints.accounts_base_url);
         let redirect_uri = format!("http://localhost:{}/spotify-auth", port);
 
         let mut params = HashMap::new();
@@ -42,31 +137,131 @@ impl SpotifyClient {
         params.insert("code", &auth_token);
         params.insert("redirect_uri", &redirect_uri);
 
+        if let SpotifyAuthFlow::Pkce { code_verifier } = &auth_flow {
+            params.insert("client_id", &client_id);
+            params.insert("code_verifier", code_verifier.expose());
+        }
+
         let client = reqwest::Client::new();
-        let base64auth = base64::engine::general_purpose::STANDARD
-            .encode(&format!("{}:{}", client_id, client_secret));
 
-        let request = client
-            .post(url)
-            .header("Content-Type", "application/x-www-form-urlencoded")
-            .header("Authorization", format!("Basic {}", base64auth))
+        let request = with_client_auth(client.post(url), &client_id, &auth_flow)
             .form(&params)
             .send()
             .await?;
 
         let mut token = request.json::<SpotifyToken>().await?;
         token.created_at = Some(Utc::now());
+        token.register_secrets();
 
         //Save access token to file
-        tokio::fs::write("spotify_token.json", serde_json::to_string(&token)?).await?;
+        token_store.save(SPOTIFY_TOKEN_FILE, &token).await?;
 
         Ok(SpotifyClient {
             client_id,
-            client_secret,
+            auth_flow,
             token: Some(token),
+            playlist_ids: HashMap::new(),
+            token_store,
+            endpoints,
         })
     }
 
+    /// Loads the saved token, or waits for the browser login when there's none. With
+    /// `replace_saved` the login always runs and the saved token is only overwritten once it
+    /// succeeds.
+    pub async fn login_async(
+        config: &Config,
+        auth_flow: SpotifyAuthFlow,
+        auth_code: oneshot::Receiver<Secret>,
+        token_store: TokenStore,
+        replace_saved: bool,
+    ) -> TwitchBotResult<Self> {
+        if !replace_saved {
+            let client = Self::load_async(
+                config.spotify.client_id.clone(),
+                auth_flow.clone(),
+                token_store.clone(),
+                SpotifyEndpoints::default(),
+            )
+            .await?;
+
+            if let Some(client) = client {
+                return Ok(client);
+            }
+        }
+
+        let code = wait_for_auth_code(auth_code, "Spotify", config.auth_timeout())
+            .await?
+            .expose()
+            .to_string();
+
+        Self::create_async(
+            config.spotify.client_id.clone(),
+            auth_flow,
+            code,
+            config.server.port,
+            token_store,
+            SpotifyEndpoints::default(),
+        )
+        .await
+    }
+
+    /// Checks the saved token against /me and the required scopes, deleting it when it was
+    /// revoked or is missing scopes so the browser flow runs again. Network errors keep the token.
+    pub async fn ensure_valid_token_async(
+        client_id: String,
+        auth_flow: SpotifyAuthFlow,
+        token_store: TokenStore,
+        endpoints: SpotifyEndpoints,
+        required_scopes: &[&str],
+    ) -> TwitchBotResult<()> {
+        let Some(token) = token_store.load::<SpotifyToken>(SPOTIFY_TOKEN_FILE).await? else {
+            return Ok(());
+        };
+        token.register_secrets();
+
+        //Tokens saved without a scope are trusted, /me still checks they work
+        let granted: Vec<&str> = match &token.scope {
+            Some(scope) => scope.split_whitespace().collect(),
+            None => required_scopes.to_vec(),
+        };
+
+        let missing_scopes = scopes::missing_scopes(required_scopes, &granted);
+        if !missing_scopes.is_empty() {
+            tracing::warn!(
+                "Spotify token is missing the scopes {}, logging in again",
+                missing_scopes.join(", ")
+            );
+            return token_store.delete(SPOTIFY_TOKEN_FILE).await;
+        }
+
+        let mut client = SpotifyClient {
+            client_id,
+            auth_flow,
+            token: Some(token),
+            playlist_ids: HashMap::new(),
+            token_store,
+            endpoints,
+        };
+
+        let result = match client.refresh_token().await {
+            Ok(()) => client.get_current_user_async().await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) if is_rejected(&e) => {
+                tracing::warn!("Saved Spotify token is no longer valid, logging in again");
+                client.token_store.delete(SPOTIFY_TOKEN_FILE).await
+            }
+            Err(e) => {
+                tracing::warn!("Could not validate Spotify token: {}", e);
+                Ok(())
+            }
+        }
+    }
+
     async fn refresh_token(&mut self) -> TwitchBotResult<()> {
         let token = self.token.clone().unwrap();
 
@@ -77,30 +272,40 @@ impl SpotifyClient {
 
         tracing::info!("Refreshing Spotify token...");
 
-        let url = format!(
-            "https://accounts.spotify.com/api/token?grant_type=refresh_token&client_id={}&refresh_token={}",
-            self.client_id, token.refresh_token.unwrap(),
-        );
+        let url = format!("{}/api/token", self.endpoints.accounts_base_url);
+        let refresh_token = token
+            .refresh_token
+            .clone()
+            .unwrap_or_else(|| String::new().into());
+
+        let mut params = HashMap::new();
+        params.insert("grant_type", "refresh_token");
+        params.insert("refresh_token", refresh_token.expose());
+        params.insert("client_id", &self.client_id);
 
         let client = reqwest::Client::new();
-        let base64auth = base64::engine::general_purpose::STANDARD
-            .encode(&format!("{}:{}", self.client_id, self.client_secret));
 
-        let request = client
-            .post(url)
-            .header("Content-Type", "application/x-www-form-urlencoded")
-            .header("Authorization", format!("Basic {}", base64auth))
-            .header("content-length", 0)
+        let request = with_client_auth(client.post(url), &self.client_id, &self.auth_flow)
+            .form(&params)
             .send()
-            .await?;
+            .await?
+            .error_for_status()?;
 
-        let mut token = request.json::<SpotifyToken>().await?;
-        token.created_at = Some(Utc::now());
+        let mut new_token = request.json::<SpotifyToken>().await?;
+        new_token.created_at = Some(Utc::now());
+
+        //Spotify only sends a new refresh token sometimes, keep the old one otherwise
+        if new_token.refresh_token.is_none() {
+            new_token.refresh_token = token.refresh_token;
+        }
+
+        let token = new_token;
+        token.register_secrets();
 
         tracing::info!("Spotify token refreshed!");
 
         //Save access token to file
-        tokio::fs::write("spotify_token.json", serde_json::to_string(&token)?).await?;
+        self.token_store.save(SPOTIFY_TOKEN_FILE, &token).await?;
 
         self.token = Some(token);
         Ok(())
@@ -109,13 +314,16 @@ impl SpotifyClient {
     pub async fn search_async(&mut self, query: &str) -> TwitchBotResult<SpotifyTrackResults> {
         let _ = self.refresh_token().await;
 
-        let url = format!("https://api.spotify.com/v1/search?q={}&type=track", query);
+        let url = format!(
+            "{}/v1/search?q={}&type=track",
+            self.endpoints.api_base_url, query
+        );
 
         let client = reqwest::Client::new();
 
         let request = client
             .get(url)
-            .bearer_auth(self.token.clone().unwrap().access_token)
+            .bearer_auth(self.token.clone().unwrap().access_token.expose())
             .send()
             .await?;
 
@@ -124,23 +332,510 @@ impl SpotifyClient {
         Ok(response.tracks)
     }
 
-    pub async fn queue_track(&mut self, track: &SpotifyTrack) -> TwitchBotResult<()> {
+    pub async fn queue_track(&mut self, track_id: &str) -> TwitchBotResult<()> {
         let _ = self.refresh_token().await;
 
         let url = format!(
-            "https://api.spotify.com/v1/me/player/queue?uri=spotify:track:{}",
-            track.id
+            "{}/v1/me/player/queue?uri=spotify:track:{}",
+            self.endpoints.api_base_url, track_id
         );
 
         let client = reqwest::Client::new();
 
         let _response = client
             .post(url)
-            .bearer_auth(self.token.clone().unwrap().access_token)
+            .bearer_auth(self.token.clone().unwrap().access_token.expose())
             .header("content-length", 0)
             .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    pub async fn get_queue_async(&mut self) -> TwitchBotResult<SpotifyQueue> {
+        let _ = self.refresh_token().await;
+
+        let url = format!("{}/v1/me/player/queue", self.endpoints.api_base_url);
+
+        let client = reqwest::Client::new();
+
+        let request = client
+            .get(url)
+            .bearer_auth(self.token.clone().unwrap().access_token.expose())
+            .send()
             .await?;
 
+        let response = request.json::<SpotifyQueue>().await?;
+
+        Ok(response)
+    }
+
+    pub async fn get_playlist_tracks_async(
+        &mut self,
+        playlist_id: &str,
+    ) -> TwitchBotResult<Vec<SpotifyTrack>> {
+        let _ = self.refresh_token().await;
+
+        let client = reqwest::Client::new();
+        let mut tracks = Vec::new();
+
+        //Spotify returns at most 100 tracks per page, follow `next` until the end
+        let mut next_url = Some(format!(
+            "{}/v1/playlists/{}/tracks?limit=100",
+            self.endpoints.api_base_url, playlist_id
+        ));
+
+        while let Some(url) = next_url {
+            let request = client
+                .get(url)
+                .bearer_auth(self.token.clone().unwrap().access_token.expose())
+                .send()
+                .await?;
+
+            let page = request.json::<SpotifyPlaylistTracksPage>().await?;
+
+            tracks.extend(
+                page.items
+                    .into_iter()
+                    .filter_map(|item| item.track)
+                    .filter(|track| !track.id.is_empty()),
+            );
+
+            next_url = page.next;
+        }
+
+        Ok(tracks)
+    }
+
+    pub async fn get_current_user_async(&mut self) -> TwitchBotResult<SpotifyUser> {
+        let _ = self.refresh_token().await;
+
+        let url = format!("{}/v1/me", self.endpoints.api_base_url);
+
+        let client = reqwest::Client::new();
+
+        let request = client
+            .get(url)
+            .bearer_auth(self.token.clone().unwrap().access_token.expose())
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response = request.json::<SpotifyUser>().await?;
+
+        Ok(response)
+    }
+
+    pub async fn get_user_playlists_async(&mut self) -> TwitchBotResult<Vec<SpotifyPlaylist>> {
+        let _ = self.refresh_token().await;
+
+        let client = reqwest::Client::new();
+        let mut playlists = Vec::new();
+
+        let mut next_url = Some(format!(
+            "{}/v1/me/playlists?limit=50",
+            self.endpoints.api_base_url
+        ));
+
+        while let Some(url) = next_url {
+            let request = client
+                .get(url)
+                .bearer_auth(self.token.clone().unwrap().access_token.expose())
+                .send()
+                .await?;
+
+            let page = request.json::<SpotifyPlaylistsPage>().await?;
+
+            playlists.extend(page.items);
+            next_url = page.next;
+        }
+
+        Ok(playlists)
+    }
+
+    pub async fn create_playlist_async(
+        &mut self,
+        name: &str,
+        description: &str,
+    ) -> TwitchBotResult<SpotifyPlaylist> {
+        let user = self.get_current_user_async().await?;
+
+        let url = format!(
+            "{}/v1/users/{}/playlists",
+            self.endpoints.api_base_url, user.id
+        );
+
+        let client = reqwest::Client::new();
+
+        let request = client
+            .post(url)
+            .bearer_auth(self.token.clone().unwrap().access_token.expose())
+            .json(&serde_json::json!({
+                "name": name,
+                "description": description,
+                "public": false,
+            }))
+            .send()
+            .await?;
+
+        let response = request.json::<SpotifyPlaylist>().await?;
+
+        Ok(response)
+    }
+
+    pub async fn add_tracks_to_playlist_async(
+        &mut self,
+        playlist_id: &str,
+        track_ids: &[String],
+    ) -> TwitchBotResult<()> {
+        let _ = self.refresh_token().await;
+
+        let url = format!(
+            "{}/v1/playlists/{}/tracks",
+            self.endpoints.api_base_url, playlist_id
+        );
+
+        let uris: Vec<String> = track_ids
+            .iter()
+            .map(|track_id| format!("spotify:track:{}", track_id))
+            .collect();
+
+        let client = reqwest::Client::new();
+
+        let _response = client
+            .post(url)
+            .bearer_auth(self.token.clone().unwrap().access_token.expose())
+            .json(&serde_json::json!({ "uris": uris }))
+            .send()
+            .await?
+            .error_for_status()?;
+
         Ok(())
     }
+
+    pub async fn skip_track_async(&mut self) -> TwitchBotResult<()> {
+        let _ = self.refresh_token().await;
+
+        let url = format!("{}/v1/me/player/next", self.endpoints.api_base_url);
+
+        let client = reqwest::Client::new();
+
+        let _response = client
+            .post(url)
+            .bearer_auth(self.token.clone().unwrap().access_token.expose())
+            .header("content-length", 0)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Looks up one of the user's playlists by name, creating it if it doesn't exist yet
+    pub async fn find_or_create_playlist_async(&mut self, name: &str) -> TwitchBotResult<String> {
+        if let Some(playlist_id) = self.playlist_ids.get(name) {
+            return Ok(playlist_id.clone());
+        }
+
+        //Reuse a playlist with the same name, so restarting the bot mid stream doesn't create another one
+        let existing = self
+            .get_user_playlists_async()
+            .await?
+            .into_iter()
+            .find(|playlist| playlist.name == name);
+
+        let playlist = match existing {
+            Some(playlist) => playlist,
+            None => {
+                let playlist = self
+                    .create_playlist_async(name, "Songs requested by chat")
+                    .await?;
+                tracing::info!("Created Spotify playlist {}", playlist.name);
+                playlist
+            }
+        };
+
+        self.playlist_ids
+            .insert(name.to_string(), playlist.id.clone());
+
+        Ok(playlist.id)
+    }
+}
+
+/// Playlist id from a share URL, a `spotify:playlist:` URI or the bare id
+pub fn playlist_id(playlist: &str) -> String {
+    //Accept https://open.spotify.com/playlist/<id>?si=... and spotify:playlist:<id>
+    let id = playlist
+        .rsplit(['/', ':'])
+        .next()
+        .unwrap_or_default()
+        .split('?')
+        .next()
+        .unwrap_or_default();
+
+    id.to_string()
+}
+
+/// The accounts service or the API answered 400/401, as opposed to being unreachable
+fn is_rejected(error: &TwitchBotError) -> bool {
+    match error {
+        TwitchBotError::ResponseError(e) => matches!(
+            e.status(),
+            Some(reqwest::StatusCode::BAD_REQUEST | reqwest::StatusCode::UNAUTHORIZED)
+        ),
+        _ => false,
+    }
+}
+
+/// The client secret flow authenticates the app with Basic auth, PKCE only sends the client id
+fn with_client_auth(
+    request: reqwest::RequestBuilder,
+    client_id: &str,
+    auth_flow: &SpotifyAuthFlow,
+) -> reqwest::RequestBuilder {
+    match auth_flow {
+        SpotifyAuthFlow::ClientSecret(client_secret) => {
+            let base64auth = base64::engine::general_purpose::STANDARD.encode(format!(
+                "{}:{}",
+                client_id,
+                client_secret.expose()
+            ));
+            request.header("Authorization", format!("Basic {}", base64auth))
+        }
+        SpotifyAuthFlow::Pkce { .. } => request,
+    }
+}
+
+#[async_trait]
+impl MusicProvider for SpotifyClient {
+    async fn search(&mut self, query: &str) -> TwitchBotResult<Option<Track>> {
+        let results = self.search_async(query).await?;
+        Ok(results.items.into_iter().next().map(Track::from))
+    }
+
+    async fn queue(&mut self, track: &Track) -> TwitchBotResult<()> {
+        self.queue_track(&track.id).await
+    }
+
+    async fn now_playing(&mut self) -> TwitchBotResult<Option<Track>> {
+        let queue = self.get_queue_async().await?;
+        Ok(queue.currently_playing.map(Track::from))
+    }
+
+    async fn skip(&mut self) -> TwitchBotResult<()> {
+        self.skip_track_async().await
+    }
+
+    async fn upcoming(&mut self) -> TwitchBotResult<Vec<Track>> {
+        let queue = self.get_queue_async().await?;
+        Ok(queue.queue.into_iter().map(Track::from).collect())
+    }
+
+    async fn playlist_tracks(&mut self, playlist: &str) -> TwitchBotResult<Vec<Track>> {
+        let tracks = self.get_playlist_tracks_async(playlist).await?;
+        Ok(tracks.into_iter().map(Track::from).collect())
+    }
+
+    async fn add_to_playlist(&mut self, playlist: &str, track: &Track) -> TwitchBotResult<()> {
+        let playlist_id = self.find_or_create_playlist_async(playlist).await?;
+        self.add_tracks_to_playlist_async(&playlist_id, std::slice::from_ref(&track.id))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_string_contains, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    const SCOPES: [&str; 2] = ["user-read-playback-state", "playlist-read-private"];
+
+    fn saved_token(age: Duration, scope: &str) -> SpotifyToken {
+        SpotifyToken {
+            access_token: "old-access".to_string().into(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            scope: Some(scope.to_string()),
+            refresh_token: Some("refresh".to_string().into()),
+            created_at: Some(Utc::now() - age),
+        }
+    }
+
+    async fn ensure_valid(server: &MockServer, token: SpotifyToken) -> Option<SpotifyToken> {
+        let dir = tempfile::tempdir().unwrap();
+        let token_store = TokenStore::new(dir.path(), None);
+        token_store.save(SPOTIFY_TOKEN_FILE, &token).await.unwrap();
+
+        let endpoints = SpotifyEndpoints {
+            accounts_base_url: server.uri(),
+            api_base_url: server.uri(),
+        };
+
+        SpotifyClient::ensure_valid_token_async(
+            "client-id".to_string(),
+            SpotifyAuthFlow::ClientSecret("client-secret".to_string().into()),
+            token_store.clone(),
+            endpoints,
+            &SCOPES,
+        )
+        .await
+        .unwrap();
+
+        token_store.load(SPOTIFY_TOKEN_FILE).await.unwrap()
+    }
+
+    async fn current_user(server: &MockServer, access_token: &str, status: u16) {
+        Mock::given(method("GET"))
+            .and(path("/v1/me"))
+            .and(header("authorization", format!("Bearer {}", access_token)))
+            .respond_with(ResponseTemplate::new(status).set_body_json(json!({ "id": "streamer" })))
+            .mount(server)
+            .await;
+    }
+
+    fn paths(requests: Vec<wiremock::Request>) -> Vec<String> {
+        requests
+            .iter()
+            .map(|request| request.url.path().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_valid_token_is_checked_without_refreshing() {
+        let server = MockServer::start().await;
+        current_user(&server, "old-access", 200).await;
+
+        let token = ensure_valid(&server, saved_token(Duration::zero(), &SCOPES.join(" ")))
+            .await
+            .unwrap();
+
+        assert_eq!(token.access_token.expose(), "old-access");
+        assert_eq!(paths(server.received_requests().await.unwrap()), ["/v1/me"]);
+    }
+
+    #[tokio::test]
+    async fn an_expired_token_is_refreshed_and_saved() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/token"))
+            .and(body_string_contains("grant_type=refresh_token"))
+            .and(body_string_contains("refresh_token=refresh"))
+            .and(header(
+                "authorization",
+                format!(
+                    "Basic {}",
+                    base64::engine::general_purpose::STANDARD.encode("client-id:client-secret")
+                ),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "new-access",
+                "token_type": "Bearer",
+                "expires_in": 3600,
+                "scope": SCOPES.join(" "),
+            })))
+            .mount(&server)
+            .await;
+        current_user(&server, "new-access", 200).await;
+
+        let token = ensure_valid(
+            &server,
+            saved_token(Duration::try_hours(2).unwrap(), &SCOPES.join(" ")),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(token.access_token.expose(), "new-access");
+        //Spotify didn't send a new refresh token, the old one is kept
+        assert_eq!(token.refresh_token.unwrap().expose(), "refresh");
+        assert_eq!(
+            paths(server.received_requests().await.unwrap()),
+            ["/api/token", "/v1/me"]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_revoked_refresh_token_is_deleted() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/token"))
+            .respond_with(
+                ResponseTemplate::new(400).set_body_json(json!({ "error": "invalid_grant" })),
+            )
+            .mount(&server)
+            .await;
+
+        let token = ensure_valid(
+            &server,
+            saved_token(Duration::try_hours(2).unwrap(), &SCOPES.join(" ")),
+        )
+        .await;
+
+        assert!(token.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_rejected_token_is_deleted_but_server_errors_keep_it() {
+        let server = MockServer::start().await;
+        current_user(&server, "old-access", 401).await;
+        let valid = saved_token(Duration::zero(), &SCOPES.join(" "));
+        assert!(ensure_valid(&server, valid.clone()).await.is_none());
+
+        let server = MockServer::start().await;
+        current_user(&server, "old-access", 503).await;
+        assert!(ensure_valid(&server, valid).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn a_token_missing_scopes_is_deleted_without_requests() {
+        let server = MockServer::start().await;
+
+        let token = ensure_valid(&server, saved_token(Duration::zero(), SCOPES[0])).await;
+
+        assert!(token.is_none());
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn playlist_id_accepts_urls_uris_and_ids() {
+        assert_eq!(
+            playlist_id("https://open.spotify.com/playlist/37i9dQZF1DX?si=abc"),
+            "37i9dQZF1DX"
+        );
+        assert_eq!(playlist_id("spotify:playlist:37i9dQZF1DX"), "37i9dQZF1DX");
+        assert_eq!(playlist_id("37i9dQZF1DX"), "37i9dQZF1DX");
+    }
+
+    #[tokio::test]
+    async fn a_failed_login_keeps_the_saved_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let token_store = TokenStore::new(dir.path(), None);
+        let token = saved_token(Duration::zero(), &SCOPES.join(" "));
+        token_store.save(SPOTIFY_TOKEN_FILE, &token).await.unwrap();
+
+        let config = Config::default();
+        let login = |replace_saved| {
+            //The browser login never finishes
+            let (_, auth_code) = oneshot::channel();
+            SpotifyClient::login_async(
+                &config,
+                SpotifyAuthFlow::pkce(),
+                auth_code,
+                token_store.clone(),
+                replace_saved,
+            )
+        };
+
+        let client = login(false).await.unwrap();
+        assert_eq!(client.token.unwrap().access_token.expose(), "old-access");
+
+        assert!(login(true).await.is_err());
+        let saved: SpotifyToken = token_store.load(SPOTIFY_TOKEN_FILE).await.unwrap().unwrap();
+        assert_eq!(saved.access_token.expose(), "old-access");
+    }
 }
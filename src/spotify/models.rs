@@ -1,30 +1,46 @@
 use std::fmt;
 
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    music::Track,
+    secret::{Secret, SecretKind},
+};
 
 #[derive(Deserialize)]
 pub struct SpotifyAuthResponse {
-    pub code: String,
+    pub code: Secret,
     pub state: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SpotifyToken {
-    pub access_token: String,
+    pub access_token: Secret,
     pub token_type: String,
     pub expires_in: i64,
     pub scope: Option<String>,
-    pub refresh_token: Option<String>,
+    pub refresh_token: Option<Secret>,
     pub created_at: Option<DateTime<Utc>>,
 }
 
+impl SpotifyToken {
+    /// Called wherever a token is read, from the token file or from Spotify
+    pub fn register_secrets(&self) {
+        self.access_token.register(SecretKind::SpotifyAccessToken);
+
+        if let Some(refresh_token) = &self.refresh_token {
+            refresh_token.register(SecretKind::SpotifyRefreshToken);
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct SpotifySearchResult {
     pub tracks: SpotifyTrackResults,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct SpotifyTrackResults {
     pub total: u64,
     pub items: Vec<SpotifyTrack>,
@@ -38,26 +54,77 @@ pub struct SpotifyArtist {
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct SpotifyTrack {
+    //Local files and unavailable tracks come back with a null id
+    #[serde(deserialize_with = "null_as_empty")]
     pub id: String,
     pub name: String,
     pub duration_ms: u64,
+    //Podcast episodes can show up in queues and playlists and have no artists
+    #[serde(default)]
     pub artists: Vec<SpotifyArtist>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpotifyPlaylistTracksPage {
+    pub total: u64,
+    pub next: Option<String>,
+    pub items: Vec<SpotifyPlaylistItem>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpotifyPlaylistItem {
+    pub track: Option<SpotifyTrack>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpotifyQueue {
+    pub currently_playing: Option<SpotifyTrack>,
+    pub queue: Vec<SpotifyTrack>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpotifyUser {
+    pub id: String,
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpotifyPlaylist {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpotifyPlaylistsPage {
+    pub total: u64,
+    pub next: Option<String>,
+    pub items: Vec<SpotifyPlaylist>,
+}
+
 impl fmt::Display for SpotifyTrack {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.write_str(&self.name)?;
-        fmt.write_str(" - ")?;
-        fmt.write_str(&self.artists[0].name)?;
+
+        if let Some(artist) = self.artists.first() {
+            fmt.write_str(" - ")?;
+            fmt.write_str(&artist.name)?;
+        }
+
         Ok(())
     }
 }
 
-impl Default for SpotifyTrackResults {
-    fn default() -> Self {
-        Self {
-            total: 0,
-            items: Vec::new(),
+impl From<SpotifyTrack> for Track {
+    fn from(track: SpotifyTrack) -> Self {
+        Track {
+            artist: track.artists.into_iter().next().map(|artist| artist.name),
+            id: track.id,
+            name: track.name,
+            duration_ms: track.duration_ms,
         }
     }
 }
+
+fn null_as_empty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::{sleep, Instant};
+
+use crate::{
+    chat::ChatSender,
+    commands::{self, Timer},
+    helix::HelixClient,
+};
+
+const TIMER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Chat messages seen in each channel, timers only fire after enough chatting
+#[derive(Debug, Clone, Default)]
+pub struct ChatActivity(Arc<Mutex<HashMap<String, u64>>>);
+
+impl ChatActivity {
+    pub fn record(&self, channel: &str) {
+        let mut counts = self.0.lock().expect("chat activity lock poisoned");
+        *counts.entry(channel.to_string()).or_default() += 1;
+    }
+
+    pub fn count(&self, channel: &str) -> u64 {
+        let counts = self.0.lock().expect("chat activity lock poisoned");
+        counts.get(channel).copied().unwrap_or_default()
+    }
+}
+
+/// Posts the enabled timers in every channel that's live, once their interval passed and enough
+/// chat messages were sent since they last fired
+pub async fn run_timers_async(
+    chat: impl ChatSender,
+    helix: HelixClient,
+    channels: Vec<String>,
+    chat_activity: ChatActivity,
+) {
+    let started = Instant::now();
+    //When each timer last fired in each channel, with the chat message count at the time
+    let mut fired: HashMap<(String, String), (Instant, u64)> = HashMap::new();
+
+    loop {
+        sleep(TIMER_CHECK_INTERVAL).await;
+
+        let timers = commands::list_timers();
+
+        for channel in &channels {
+            let now = Instant::now();
+            let messages = chat_activity.count(channel);
+
+            //Only ask Twitch when a timer would be due in a live channel, most checks end here
+            let waiting: Vec<_> = timers
+                .iter()
+                .map(|(name, timer)| {
+                    let last_fired = fired
+                        .get(&(name.clone(), channel.clone()))
+                        .copied()
+                        .unwrap_or((started, 0));
+
+                    (name, timer, last_fired)
+                })
+                .filter(|(_, timer, last_fired)| is_due(timer, now, *last_fired, messages, true))
+                .collect();
+
+            if waiting.is_empty() {
+                continue;
+            }
+
+            let live = match helix.get_stream_async(channel).await {
+                Ok(stream) => stream.is_some(),
+                Err(e) => {
+                    tracing::warn!("Could not check if #{} is live: {}", channel, e);
+                    false
+                }
+            };
+
+            for (name, timer, last_fired) in waiting {
+                if !is_due(timer, now, last_fired, messages, live) {
+                    continue;
+                }
+
+                tracing::info!("Posting timer {} in #{}", name, channel);
+
+                if let Err(e) = chat.say(channel.clone(), timer.message.clone()).await {
+                    tracing::warn!("{}", e);
+                }
+
+                fired.insert((name.clone(), channel.clone()), (Instant::now(), messages));
+            }
+        }
+    }
+}
+
+/// Whether a timer posts now. `last_fired` is when it last posted in the channel, or when the
+/// bot started, with the chat message count at that time.
+fn is_due(
+    timer: &Timer,
+    now: Instant,
+    last_fired: (Instant, u64),
+    messages: u64,
+    live: bool,
+) -> bool {
+    let (fired_at, fired_messages) = last_fired;
+
+    timer.enabled
+        && live
+        && now - fired_at >= timer.interval()
+        && messages.saturating_sub(fired_messages) >= u64::from(timer.min_chat_messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer(interval_minutes: u32, min_chat_messages: u32) -> Timer {
+        Timer {
+            message: "Sigam o canal".to_string(),
+            interval_minutes,
+            min_chat_messages,
+            enabled: true,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timer_waits_for_its_interval() {
+        let timer = timer(10, 0);
+        let last_fired = (Instant::now(), 0);
+
+        tokio::time::advance(Duration::from_secs(10 * 60 - 1)).await;
+        assert!(!is_due(&timer, Instant::now(), last_fired, 0, true));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(is_due(&timer, Instant::now(), last_fired, 0, true));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timer_waits_for_enough_chat_since_it_last_fired() {
+        let timer = timer(5, 3);
+        let last_fired = (Instant::now(), 10);
+        tokio::time::advance(Duration::from_secs(5 * 60)).await;
+
+        assert!(!is_due(&timer, Instant::now(), last_fired, 12, true));
+        assert!(is_due(&timer, Instant::now(), last_fired, 13, true));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timer_only_posts_while_live_and_enabled() {
+        let mut timer = timer(1, 0);
+        let last_fired = (Instant::now(), 0);
+        tokio::time::advance(Duration::from_secs(60)).await;
+
+        assert!(!is_due(&timer, Instant::now(), last_fired, 0, false));
+
+        timer.enabled = false;
+        assert!(!is_due(&timer, Instant::now(), last_fired, 0, true));
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use base64::Engine;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{TwitchBotError, TwitchBotResult};
+
+pub const TWITCH_TOKEN_FILE: &str = "twitch_token.json";
+pub const SPOTIFY_TOKEN_FILE: &str = "spotify_token.json";
+
+/// Marks token files written with encryption enabled
+const ENCRYPTED_PREFIX: &str = "encrypted:v1:";
+const NONCE_LENGTH: usize = 24;
+
+/// Reads and writes OAuth tokens in a single directory, optionally encrypted at rest
+#[derive(Clone)]
+pub struct TokenStore {
+    directory: PathBuf,
+    cipher: Option<XChaCha20Poly1305>,
+}
+
+impl TokenStore {
+    /// Any passphrase works as the key, it is hashed into a 256 bit key
+    pub fn new(directory: impl Into<PathBuf>, encryption_key: Option<&str>) -> Self {
+        let cipher = encryption_key.map(|key| {
+            let key = Sha256::digest(key.as_bytes());
+            XChaCha20Poly1305::new(&key)
+        });
+
+        Self {
+            directory: directory.into(),
+            cipher,
+        }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.directory.join(name)
+    }
+
+    pub async fn exists(&self, name: &str) -> bool {
+        tokio::fs::metadata(self.path(name)).await.is_ok()
+    }
+
+    pub async fn load<T: DeserializeOwned>(&self, name: &str) -> TwitchBotResult<Option<T>> {
+        let path = self.path(name);
+
+        let contents = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        //Plain text files are still readable, they get encrypted on the next save
+        let json = match contents.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(encrypted) => self.decrypt(encrypted.trim(), &path)?,
+            None => contents,
+        };
+
+        Ok(Some(serde_json::from_str(&json)?))
+    }
+
+    pub async fn save<T: Serialize>(&self, name: &str, value: &T) -> TwitchBotResult<()> {
+        let json = serde_json::to_string(value)?;
+
+        let contents = match &self.cipher {
+            Some(cipher) => format!("{}{}", ENCRYPTED_PREFIX, encrypt(cipher, &json)?),
+            None => json,
+        };
+
+        tokio::fs::create_dir_all(&self.directory).await?;
+
+        //Write next to the real file and swap it in, so a crash never leaves a half written token
+        let temp_path = self.path(&format!("{}.tmp", name));
+        write_private(&temp_path, contents.as_bytes()).await?;
+        tokio::fs::rename(&temp_path, self.path(name)).await?;
+
+        Ok(())
+    }
+
+    pub async fn delete(&self, name: &str) -> TwitchBotResult<()> {
+        match tokio::fs::remove_file(self.path(name)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn decrypt(&self, encrypted: &str, path: &Path) -> TwitchBotResult<String> {
+        let error = || TwitchBotError::TokenDecryptError(path.display().to_string());
+
+        let cipher = self.cipher.as_ref().ok_or_else(error)?;
+
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(encrypted)
+            .map_err(|_| error())?;
+
+        if bytes.len() < NONCE_LENGTH {
+            return Err(error());
+        }
+
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+        let plaintext = cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| error())?;
+
+        String::from_utf8(plaintext).map_err(|_| error())
+    }
+}
+
+impl fmt::Debug for TokenStore {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("TokenStore")
+            .field("directory", &self.directory)
+            .field("encrypted", &self.cipher.is_some())
+            .finish()
+    }
+}
+
+fn encrypt(cipher: &XChaCha20Poly1305, json: &str) -> TwitchBotResult<String> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let ciphertext = cipher
+        .encrypt(&nonce, json.as_bytes())
+        .map_err(|_| TwitchBotError::TokenEncryptError())?;
+
+    let mut bytes = nonce.to_vec();
+    bytes.extend(ciphertext);
+
+    Ok(base64::engine::general_purpose::STANDARD.encode(bytes))
+}
+
+/// Writes a file only the current user can read
+async fn write_private(path: &Path, contents: &[u8]) -> TwitchBotResult<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path).await?;
+
+    //mode() only applies to new files, tighten tokens written by older versions too
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await?;
+    }
+
+    tokio::io::AsyncWriteExt::write_all(&mut file, contents).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Token {
+        access_token: String,
+    }
+
+    fn token(value: &str) -> Token {
+        Token {
+            access_token: value.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn saved_tokens_load_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::new(dir.path().join("tokens"), None);
+
+        assert_eq!(store.load::<Token>("token.json").await.unwrap(), None);
+
+        store.save("token.json", &token("abc")).await.unwrap();
+        assert_eq!(
+            store.load::<Token>("token.json").await.unwrap(),
+            Some(token("abc"))
+        );
+
+        store.delete("token.json").await.unwrap();
+        assert!(!store.exists("token.json").await);
+    }
+
+    #[tokio::test]
+    async fn encrypted_tokens_load_back_and_are_not_plain_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::new(dir.path(), Some("passphrase"));
+
+        store.save("token.json", &token("abc")).await.unwrap();
+
+        let contents = std::fs::read_to_string(store.path("token.json")).unwrap();
+        assert!(contents.starts_with(ENCRYPTED_PREFIX));
+        assert!(!contents.contains("abc"));
+
+        assert_eq!(
+            store.load::<Token>("token.json").await.unwrap(),
+            Some(token("abc"))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_wrong_key_fails_to_decrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        TokenStore::new(dir.path(), Some("passphrase"))
+            .save("token.json", &token("abc"))
+            .await
+            .unwrap();
+
+        for store in [
+            TokenStore::new(dir.path(), Some("another passphrase")),
+            TokenStore::new(dir.path(), None),
+        ] {
+            assert!(matches!(
+                store.load::<Token>("token.json").await,
+                Err(TwitchBotError::TokenDecryptError(_))
+            ));
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn token_files_are_only_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::new(dir.path(), None);
+
+        //Written by an older version with the default permissions
+        std::fs::write(store.path("token.json"), "{}").unwrap();
+        std::fs::set_permissions(
+            store.path("token.json"),
+            std::fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+
+        store.save("token.json", &token("abc")).await.unwrap();
+
+        let mode = std::fs::metadata(store.path("token.json"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn saving_leaves_no_partial_file_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::new(dir.path(), None);
+        store.save("token.json", &token("old")).await.unwrap();
+
+        //A crash halfway through a save only leaves the temporary file
+        std::fs::write(store.path("token.json.tmp"), "{\"access_to").unwrap();
+        assert_eq!(
+            store.load::<Token>("token.json").await.unwrap(),
+            Some(token("old"))
+        );
+
+        store.save("token.json", &token("new")).await.unwrap();
+
+        let files: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(files, ["token.json"]);
+        assert_eq!(
+            store.load::<Token>("token.json").await.unwrap(),
+            Some(token("new"))
+        );
+    }
+}
@@ -1,43 +1,141 @@
+use std::str::FromStr;
+
 use async_trait::async_trait;
 use chrono::{Duration, Utc};
 use serde::Deserialize;
-use tokio::io::AsyncReadExt;
+use tokio::{sync::oneshot, time::sleep};
 use twitch_irc::login::{TokenStorage, UserAccessToken};
 
-use crate::error::TwitchBotResult;
+use crate::{
+    config::Config,
+    error::{TwitchBotError, TwitchBotResult},
+    request_endpoints::wait_for_auth_code,
+    scopes,
+    secret::{self, Secret, SecretKind},
+    token_store::{TokenStore, TWITCH_TOKEN_FILE},
+};
+
+pub const TWITCH_ID_BASE_URL: &str = "https://id.twitch.tv";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TwitchAuthFlow {
+    /// Opens the browser and waits for the redirect to the local server
+    Browser,
+    /// Prints a code to enter on another device, for headless machines
+    #[serde(rename = "device")]
+    DeviceCode,
+}
+
+impl FromStr for TwitchAuthFlow {
+    type Err = TwitchBotError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "browser" => Ok(Self::Browser),
+            "device" => Ok(Self::DeviceCode),
+            _ => Err(TwitchBotError::InvalidConfig(format!(
+                "unknown twitch auth flow {}, expected browser or device",
+                value
+            ))),
+        }
+    }
+}
 
 #[derive(Deserialize)]
 pub struct TwitchUserAuthResponse {
-    pub code: String,
+    pub code: Secret,
     pub scope: String,
     pub state: Option<String>,
 }
 
-#[derive(Debug)]
+/// Keeps the Twitch token on disk, so tokens refreshed by twitch-irc survive a restart
+#[derive(Debug, Clone)]
 pub struct TwitchTokenStorage {
-    pub token: UserAccessToken,
+    pub token_store: TokenStore,
 }
 
 #[async_trait]
 impl TokenStorage for TwitchTokenStorage {
-    type LoadError = crate::error::TwitchBotError;
-    type UpdateError = crate::error::TwitchBotError;
+    type LoadError = TwitchBotError;
+    type UpdateError = TwitchBotError;
 
     async fn load_token(&mut self) -> Result<UserAccessToken, Self::LoadError> {
-        Ok(self.token.clone())
+        let token: UserAccessToken = self
+            .token_store
+            .load(TWITCH_TOKEN_FILE)
+            .await?
+            .ok_or(TwitchBotError::TwitchTokenLoadError())?;
+
+        register_token(&token);
+        Ok(token)
     }
 
     async fn update_token(&mut self, token: &UserAccessToken) -> Result<(), Self::UpdateError> {
-        self.token = token.clone();
-        Ok(())
+        register_token(token);
+        tracing::info!("Twitch token refreshed!");
+
+        self.token_store
+            .save(TWITCH_TOKEN_FILE, token)
+            .await
+            .map_err(|e| {
+                tracing::error!("Could not save refreshed Twitch token: {}", e);
+                TwitchBotError::TwitchTokenUpdateError()
+            })
     }
 }
 
+/// UserAccessToken comes from twitch-irc and can't hold a Secret, so its values are registered
+/// for redaction by hand
+fn register_token(token: &UserAccessToken) {
+    secret::register(SecretKind::TwitchAccessToken, &token.access_token);
+    secret::register(SecretKind::TwitchRefreshToken, &token.refresh_token);
+}
+
 #[derive(Deserialize)]
 struct TwitchOAuthResponse {
-    access_token: String,
+    access_token: Secret,
     expires_in: i64,
-    refresh_token: String,
+    refresh_token: Secret,
+}
+
+impl From<TwitchOAuthResponse> for UserAccessToken {
+    fn from(auth_response: TwitchOAuthResponse) -> Self {
+        let token = UserAccessToken {
+            access_token: auth_response.access_token.expose().to_string(),
+            created_at: Utc::now(),
+            expires_at: Some(Utc::now() + Duration::try_seconds(auth_response.expires_in).unwrap()),
+            refresh_token: auth_response.refresh_token.expose().to_string(),
+        };
+
+        register_token(&token);
+        token
+    }
+}
+
+#[derive(Deserialize)]
+struct TwitchDeviceCodeResponse {
+    device_code: Secret,
+    expires_in: i64,
+    interval: u64,
+    user_code: String,
+    verification_uri: String,
+}
+
+/// What /oauth2/validate reports about a token
+#[derive(Debug, Deserialize)]
+pub struct TwitchTokenValidation {
+    pub client_id: String,
+    pub login: String,
+    pub user_id: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize)]
+struct TwitchErrorResponse {
+    message: String,
 }
 
 pub async fn get_user_access_token_async(
@@ -45,19 +143,12 @@ pub async fn get_user_access_token_async(
     client_secret: String,
     user_auth_code: String,
     port: u16,
+    id_base_url: &str,
+    token_store: &TokenStore,
 ) -> TwitchBotResult<UserAccessToken> {
-    //Get token from file, if it doens't exist, make request
-    if let Ok(mut token_file) = tokio::fs::File::open("twitch_token.json").await {
-        let mut contents = String::new();
-        token_file.read_to_string(&mut contents).await?;
-        let token: UserAccessToken = serde_json::from_str(&contents)?;
-
-        return Ok(token);
-    }
-
     //Make http request for access token
     let client = reqwest::Client::new();
-    let url = "https://id.twitch.tv/oauth2/token";
+    let url = format!("{}/oauth2/token", id_base_url);
     let body = format!("client_id={}&client_secret={}&code={}&grant_type=authorization_code&redirect_uri=http://localhost:{}/auth",
                        client_id, client_secret, user_auth_code, port);
 
@@ -65,15 +156,690 @@ pub async fn get_user_access_token_async(
 
     let auth_response = auth_request.json::<TwitchOAuthResponse>().await?;
 
-    let access_token = UserAccessToken {
-        access_token: auth_response.access_token,
-        created_at: Utc::now(),
-        expires_at: Some(Utc::now() + Duration::try_seconds(auth_response.expires_in).unwrap()),
-        refresh_token: auth_response.refresh_token,
-    };
+    let access_token = UserAccessToken::from(auth_response);
 
     //Save access token to file
-    tokio::fs::write("twitch_token.json", serde_json::to_string(&access_token)?).await?;
+    token_store.save(TWITCH_TOKEN_FILE, &access_token).await?;
 
     Ok(access_token)
 }
+
+/// Device Code Grant flow, the user opens the printed URL on any device and enters the code
+/// while we poll the token endpoint
+pub async fn get_device_access_token_async(
+    client_id: &str,
+    client_secret: &str,
+    id_base_url: &str,
+    token_store: &TokenStore,
+    scopes: &[&str],
+) -> TwitchBotResult<UserAccessToken> {
+    let client = reqwest::Client::new();
+    let scopes = scopes.join(" ");
+
+    let device_request = client
+        .post(format!("{}/oauth2/device", id_base_url))
+        .form(&[("client_id", client_id), ("scopes", &scopes)])
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let device = device_request.json::<TwitchDeviceCodeResponse>().await?;
+    device.device_code.register(SecretKind::TwitchDeviceCode);
+
+    tracing::info!(
+        "To log in to Twitch open {} and enter the code {}",
+        device.verification_uri,
+        device.user_code
+    );
+
+    let expires_at = Utc::now() + Duration::try_seconds(device.expires_in).unwrap();
+    let mut interval = device.interval.max(1);
+
+    let mut params = vec![
+        ("client_id", client_id),
+        ("scopes", &scopes),
+        ("device_code", device.device_code.expose()),
+        ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+    ];
+
+    if !client_secret.is_empty() {
+        params.push(("client_secret", client_secret));
+    }
+
+    loop {
+        sleep(std::time::Duration::from_secs(interval)).await;
+
+        if Utc::now() > expires_at {
+            return Err(TwitchBotError::TwitchDeviceAuthError(
+                "the device code expired before it was entered".to_string(),
+            ));
+        }
+
+        let token_request = client
+            .post(format!("{}/oauth2/token", id_base_url))
+            .form(&params)
+            .send()
+            .await?;
+
+        if token_request.status().is_success() {
+            let auth_response = token_request.json::<TwitchOAuthResponse>().await?;
+            let access_token = UserAccessToken::from(auth_response);
+
+            //Save access token to file
+            token_store.save(TWITCH_TOKEN_FILE, &access_token).await?;
+
+            return Ok(access_token);
+        }
+
+        let error = token_request.json::<TwitchErrorResponse>().await?;
+        interval = next_poll_interval(interval, error.message)?;
+    }
+}
+
+/// How long to wait before polling again after Twitch refused the device code, an error when
+/// the login can't succeed anymore
+fn next_poll_interval(interval: u64, message: String) -> TwitchBotResult<u64> {
+    match message.as_str() {
+        "authorization_pending" => {
+            tracing::info!("Waiting for Twitch device login");
+            Ok(interval)
+        }
+        "slow_down" => Ok(interval + 5),
+        _ => Err(TwitchBotError::TwitchDeviceAuthError(message)),
+    }
+}
+
+/// Returns None when Twitch says the token is invalid or revoked
+pub async fn validate_token_async(
+    access_token: &str,
+    id_base_url: &str,
+) -> TwitchBotResult<Option<TwitchTokenValidation>> {
+    let client = reqwest::Client::new();
+
+    let validate_request = client
+        .get(format!("{}/oauth2/validate", id_base_url))
+        .header("Authorization", format!("OAuth {}", access_token))
+        .send()
+        .await?;
+
+    if validate_request.status() == reqwest::StatusCode::UNAUTHORIZED {
+        return Ok(None);
+    }
+
+    let validation = validate_request
+        .error_for_status()?
+        .json::<TwitchTokenValidation>()
+        .await?;
+
+    Ok(Some(validation))
+}
+
+/// Returns None when the refresh token was rejected
+pub async fn refresh_token_async(
+    client_id: &str,
+    client_secret: &str,
+    refresh_token: &str,
+    id_base_url: &str,
+) -> TwitchBotResult<Option<UserAccessToken>> {
+    let client = reqwest::Client::new();
+
+    let token_request = client
+        .post(format!("{}/oauth2/token", id_base_url))
+        .form(&[
+            ("client_id", client_id),
+            ("client_secret", client_secret),
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+        ])
+        .send()
+        .await?;
+
+    if token_request.status().is_client_error() {
+        return Ok(None);
+    }
+
+    let auth_response = token_request
+        .error_for_status()?
+        .json::<TwitchOAuthResponse>()
+        .await?;
+
+    Ok(Some(UserAccessToken::from(auth_response)))
+}
+
+/// Checks the saved token on startup. An expired token is refreshed, a revoked one or one
+/// missing one of the required scopes is deleted so the login flow runs again. Network errors
+/// keep the token.
+pub async fn ensure_valid_token_async(
+    client_id: &str,
+    client_secret: &str,
+    id_base_url: &str,
+    token_store: &TokenStore,
+    required_scopes: &[&str],
+) -> TwitchBotResult<()> {
+    let Some(token) = token_store
+        .load::<UserAccessToken>(TWITCH_TOKEN_FILE)
+        .await?
+    else {
+        return Ok(());
+    };
+    register_token(&token);
+
+    let mut validation = match validate_token_async(&token.access_token, id_base_url).await {
+        Ok(validation) => validation,
+        Err(e) => {
+            tracing::warn!("Could not validate Twitch token: {}", e);
+            return Ok(());
+        }
+    };
+
+    //Access tokens expire after a few hours, that alone shouldn't require a new login
+    if validation.is_none() {
+        match refresh_token_async(client_id, client_secret, &token.refresh_token, id_base_url).await
+        {
+            Ok(Some(refreshed)) => {
+                register_token(&refreshed);
+                token_store.save(TWITCH_TOKEN_FILE, &refreshed).await?;
+
+                validation = match validate_token_async(&refreshed.access_token, id_base_url).await
+                {
+                    Ok(validation) => validation,
+                    Err(e) => {
+                        tracing::warn!("Could not validate refreshed Twitch token: {}", e);
+                        return Ok(());
+                    }
+                };
+            }
+            Ok(None) => (),
+            Err(e) => {
+                tracing::warn!("Could not refresh Twitch token: {}", e);
+                return Ok(());
+            }
+        }
+    }
+
+    let Some(validation) = validation else {
+        tracing::warn!("Saved Twitch token is no longer valid, logging in again");
+        return token_store.delete(TWITCH_TOKEN_FILE).await;
+    };
+
+    let granted: Vec<&str> = validation.scopes.iter().map(String::as_str).collect();
+    let missing_scopes = scopes::missing_scopes(required_scopes, &granted);
+
+    if !missing_scopes.is_empty() {
+        tracing::warn!(
+            "Twitch token is missing the scopes {}, logging in again",
+            missing_scopes.join(", ")
+        );
+        return token_store.delete(TWITCH_TOKEN_FILE).await;
+    }
+
+    tracing::info!("Twitch token valid for {}", validation.login);
+    Ok(())
+}
+
+/// Makes sure a Twitch token is saved, running the configured login flow if there's none. With
+/// `replace_saved` the login always runs and the saved token is only overwritten once it succeeds.
+pub async fn login_async(
+    config: &Config,
+    auth_code: oneshot::Receiver<Secret>,
+    token_store: &TokenStore,
+    replace_saved: bool,
+) -> TwitchBotResult<()> {
+    if !replace_saved && token_store.exists(TWITCH_TOKEN_FILE).await {
+        return Ok(());
+    }
+
+    let client_secret = config.twitch_client_secret();
+
+    match config.twitch.auth_flow {
+        TwitchAuthFlow::Browser => {
+            let code = wait_for_auth_code(auth_code, "Twitch", config.auth_timeout()).await?;
+            get_user_access_token_async(
+                config.twitch.client_id.clone(),
+                client_secret,
+                code.expose().to_string(),
+                config.server.port,
+                &config.twitch.id_base_url,
+                token_store,
+            )
+            .await?;
+        }
+        TwitchAuthFlow::DeviceCode => {
+            get_device_access_token_async(
+                &config.twitch.client_id,
+                &client_secret,
+                &config.twitch.id_base_url,
+                token_store,
+                &config.features.twitch_scopes(),
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_string_contains, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    fn user_token() -> UserAccessToken {
+        UserAccessToken {
+            access_token: "access-token".to_string(),
+            refresh_token: "refresh-token".to_string(),
+            created_at: Utc::now(),
+            expires_at: Some(Utc::now() + Duration::try_hours(4).unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshed_tokens_are_saved_and_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = TwitchTokenStorage {
+            token_store: TokenStore::new(dir.path(), Some("passphrase")),
+        };
+
+        assert!(matches!(
+            storage.load_token().await,
+            Err(TwitchBotError::TwitchTokenLoadError())
+        ));
+
+        let token = user_token();
+        storage.update_token(&token).await.unwrap();
+
+        let loaded = storage.load_token().await.unwrap();
+        assert_eq!(loaded.access_token, token.access_token);
+        assert_eq!(loaded.refresh_token, token.refresh_token);
+        assert_eq!(loaded.expires_at, token.expires_at);
+    }
+
+    #[tokio::test]
+    async fn a_failed_save_is_an_update_error() {
+        let dir = tempfile::tempdir().unwrap();
+        //The token directory can't be created under a regular file
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+
+        let mut storage = TwitchTokenStorage {
+            token_store: TokenStore::new(file.join("tokens"), None),
+        };
+
+        assert!(matches!(
+            storage.update_token(&user_token()).await,
+            Err(TwitchBotError::TwitchTokenUpdateError())
+        ));
+    }
+
+    async fn device_server() -> MockServer {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/oauth2/device"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "device_code": "device-code",
+                "expires_in": 1800,
+                "interval": 5,
+                "user_code": "ABCDEFGH",
+                "verification_uri": "https://www.twitch.tv/activate?device-code=ABCDEFGH",
+            })))
+            .mount(&server)
+            .await;
+
+        server
+    }
+
+    /// Twitch answers 400 with the reason while the device code isn't usable
+    async fn token_error(server: &MockServer, message: &str, times: u64) {
+        Mock::given(method("POST"))
+            .and(path("/oauth2/token"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "status": 400,
+                "message": message,
+            })))
+            .up_to_n_times(times)
+            .mount(server)
+            .await;
+    }
+
+    async fn token_success(server: &MockServer) {
+        Mock::given(method("POST"))
+            .and(path("/oauth2/token"))
+            .and(body_string_contains("device_code=device-code"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "access-token",
+                "expires_in": 14400,
+                "refresh_token": "refresh-token",
+                "scope": ["chat:read", "chat:edit"],
+                "token_type": "bearer",
+            })))
+            .mount(server)
+            .await;
+    }
+
+    async fn device_login(
+        server: &MockServer,
+        token_store: &TokenStore,
+    ) -> TwitchBotResult<UserAccessToken> {
+        get_device_access_token_async(
+            "client-id",
+            "",
+            &server.uri(),
+            token_store,
+            &["chat:read", "chat:edit"],
+        )
+        .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn device_login_polls_while_authorization_is_pending() {
+        let server = device_server().await;
+        token_error(&server, "authorization_pending", 2).await;
+        token_success(&server).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let token_store = TokenStore::new(dir.path(), None);
+
+        let token = device_login(&server, &token_store).await.unwrap();
+
+        assert_eq!(token.access_token, "access-token");
+        assert_eq!(token.refresh_token, "refresh-token");
+        assert_eq!(server.received_requests().await.unwrap().len(), 4);
+
+        let saved: UserAccessToken = token_store.load(TWITCH_TOKEN_FILE).await.unwrap().unwrap();
+        assert_eq!(saved.access_token, "access-token");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn device_login_keeps_polling_after_slow_down() {
+        let server = device_server().await;
+        token_error(&server, "slow_down", 2).await;
+        token_success(&server).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let token_store = TokenStore::new(dir.path(), None);
+
+        device_login(&server, &token_store).await.unwrap();
+
+        assert_eq!(server.received_requests().await.unwrap().len(), 4);
+    }
+
+    #[test]
+    fn slow_down_makes_the_polling_interval_longer() {
+        let next = |message: &str| next_poll_interval(5, message.to_string());
+
+        assert_eq!(next("authorization_pending").unwrap(), 5);
+        assert_eq!(next("slow_down").unwrap(), 10);
+        assert!(matches!(
+            next("expired_token"),
+            Err(TwitchBotError::TwitchDeviceAuthError(_))
+        ));
+        assert!(next("invalid device code").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_expired_device_code_fails_the_login() {
+        let server = device_server().await;
+        token_error(&server, "authorization_pending", 1).await;
+        token_error(&server, "expired_token", 1).await;
+        token_success(&server).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let token_store = TokenStore::new(dir.path(), None);
+
+        match device_login(&server, &token_store).await {
+            Err(TwitchBotError::TwitchDeviceAuthError(message)) => {
+                assert_eq!(message, "expired_token")
+            }
+            other => panic!("expected a device auth error, got {:?}", other.map(|_| ())),
+        }
+        assert!(!token_store.exists(TWITCH_TOKEN_FILE).await);
+    }
+
+    #[tokio::test]
+    async fn a_failed_login_keeps_the_saved_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let token_store = TokenStore::new(dir.path(), None);
+        token_store
+            .save(TWITCH_TOKEN_FILE, &user_token())
+            .await
+            .unwrap();
+
+        let config = Config::default();
+        let login = |replace_saved| {
+            //The browser login never finishes
+            let (_, auth_code) = oneshot::channel();
+            login_async(&config, auth_code, &token_store, replace_saved)
+        };
+
+        login(false).await.unwrap();
+        assert!(login(true).await.is_err());
+
+        let saved: UserAccessToken = token_store.load(TWITCH_TOKEN_FILE).await.unwrap().unwrap();
+        assert_eq!(saved.access_token, "access-token");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn device_login_uses_the_configured_id_url() {
+        let server = device_server().await;
+        token_success(&server).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let token_store = TokenStore::new(dir.path(), None);
+        let mut config = Config::default();
+        config.twitch.auth_flow = TwitchAuthFlow::DeviceCode;
+        config.twitch.id_base_url = server.uri();
+
+        let (_, auth_code) = oneshot::channel();
+        login_async(&config, auth_code, &token_store, true)
+            .await
+            .unwrap();
+
+        assert!(token_store.exists(TWITCH_TOKEN_FILE).await);
+    }
+
+    const REQUIRED_SCOPES: [&str; 2] = ["chat:edit", "chat:read"];
+
+    async fn validate_response(
+        server: &MockServer,
+        access_token: &str,
+        response: ResponseTemplate,
+    ) {
+        Mock::given(method("GET"))
+            .and(path("/oauth2/validate"))
+            .and(header("Authorization", format!("OAuth {}", access_token)))
+            .respond_with(response)
+            .mount(server)
+            .await;
+    }
+
+    fn validation(scopes: &[&str]) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "client_id": "client-id",
+            "login": "bot",
+            "user_id": "1",
+            "scopes": scopes,
+            "expires_in": 3600,
+        }))
+    }
+
+    fn invalid_token() -> ResponseTemplate {
+        ResponseTemplate::new(401).set_body_json(json!({
+            "status": 401,
+            "message": "invalid access token",
+        }))
+    }
+
+    async fn refresh_response(server: &MockServer, response: ResponseTemplate) {
+        Mock::given(method("POST"))
+            .and(path("/oauth2/token"))
+            .and(body_string_contains("grant_type=refresh_token"))
+            .and(body_string_contains("refresh_token=refresh-token"))
+            .and(body_string_contains("client_secret=client-secret"))
+            .respond_with(response)
+            .mount(server)
+            .await;
+    }
+
+    fn refreshed() -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({
+            "access_token": "new-access-token",
+            "expires_in": 14400,
+            "refresh_token": "new-refresh-token",
+            "scope": REQUIRED_SCOPES,
+            "token_type": "bearer",
+        }))
+    }
+
+    /// Runs the startup check on a saved `user_token`, returning what's left on disk
+    async fn check_token(server: &MockServer) -> Option<UserAccessToken> {
+        let dir = tempfile::tempdir().unwrap();
+        let token_store = TokenStore::new(dir.path(), None);
+        token_store
+            .save(TWITCH_TOKEN_FILE, &user_token())
+            .await
+            .unwrap();
+
+        ensure_valid_token_async(
+            "client-id",
+            "client-secret",
+            &server.uri(),
+            &token_store,
+            &REQUIRED_SCOPES,
+        )
+        .await
+        .unwrap();
+
+        token_store.load(TWITCH_TOKEN_FILE).await.unwrap()
+    }
+
+    async fn requests(server: &MockServer) -> Vec<String> {
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| format!("{} {}", request.method, request.url.path()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn valid_token_is_only_validated() {
+        let server = MockServer::start().await;
+        validate_response(&server, "access-token", validation(&REQUIRED_SCOPES)).await;
+
+        let saved = check_token(&server).await.unwrap();
+
+        assert_eq!(saved.access_token, "access-token");
+        assert_eq!(requests(&server).await, vec!["GET /oauth2/validate"]);
+    }
+
+    #[tokio::test]
+    async fn invalid_token_is_refreshed_and_saved() {
+        let server = MockServer::start().await;
+        validate_response(&server, "access-token", invalid_token()).await;
+        validate_response(&server, "new-access-token", validation(&REQUIRED_SCOPES)).await;
+        refresh_response(&server, refreshed()).await;
+
+        let saved = check_token(&server).await.unwrap();
+
+        assert_eq!(saved.access_token, "new-access-token");
+        assert_eq!(saved.refresh_token, "new-refresh-token");
+        assert_eq!(
+            requests(&server).await,
+            vec![
+                "GET /oauth2/validate",
+                "POST /oauth2/token",
+                "GET /oauth2/validate"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn revoked_refresh_token_is_deleted() {
+        let server = MockServer::start().await;
+        validate_response(&server, "access-token", invalid_token()).await;
+        refresh_response(
+            &server,
+            ResponseTemplate::new(400).set_body_json(json!({
+                "status": 400,
+                "message": "Invalid refresh token",
+            })),
+        )
+        .await;
+
+        assert!(check_token(&server).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn token_missing_a_scope_is_deleted() {
+        let server = MockServer::start().await;
+        validate_response(&server, "access-token", validation(&["chat:read"])).await;
+
+        assert!(check_token(&server).await.is_none());
+        assert_eq!(requests(&server).await, vec!["GET /oauth2/validate"]);
+    }
+
+    #[tokio::test]
+    async fn refreshed_token_missing_a_scope_is_deleted() {
+        let server = MockServer::start().await;
+        validate_response(&server, "access-token", invalid_token()).await;
+        validate_response(&server, "new-access-token", validation(&["chat:read"])).await;
+        refresh_response(&server, refreshed()).await;
+
+        assert!(check_token(&server).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn server_errors_keep_the_token() {
+        let server = MockServer::start().await;
+        validate_response(&server, "access-token", ResponseTemplate::new(503)).await;
+
+        let saved = check_token(&server).await.unwrap();
+        assert_eq!(saved.access_token, "access-token");
+
+        //The refresh itself failing
+        let server = MockServer::start().await;
+        validate_response(&server, "access-token", invalid_token()).await;
+        refresh_response(&server, ResponseTemplate::new(503)).await;
+
+        let saved = check_token(&server).await.unwrap();
+        assert_eq!(saved.access_token, "access-token");
+    }
+
+    #[tokio::test]
+    async fn failed_validation_after_a_refresh_keeps_the_new_token() {
+        let server = MockServer::start().await;
+        validate_response(&server, "access-token", invalid_token()).await;
+        validate_response(&server, "new-access-token", ResponseTemplate::new(503)).await;
+        refresh_response(&server, refreshed()).await;
+
+        let saved = check_token(&server).await.unwrap();
+        assert_eq!(saved.access_token, "new-access-token");
+    }
+
+    #[tokio::test]
+    async fn unreachable_id_server_keeps_the_token() {
+        let server = MockServer::start().await;
+        let uri = server.uri();
+        drop(server);
+
+        let dir = tempfile::tempdir().unwrap();
+        let token_store = TokenStore::new(dir.path(), None);
+        token_store
+            .save(TWITCH_TOKEN_FILE, &user_token())
+            .await
+            .unwrap();
+
+        ensure_valid_token_async("client-id", "client-secret", &uri, &token_store, &[])
+            .await
+            .unwrap();
+
+        assert!(token_store.exists(TWITCH_TOKEN_FILE).await);
+    }
+}
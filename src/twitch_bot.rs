@@ -1,136 +1,821 @@
-use std::{sync::Arc, time::Duration};
+use std::sync::Arc;
 
-use tokio::{sync::Mutex, time::sleep};
+use chrono::{DateTime, Utc};
+use tokio::sync::Mutex;
 use twitch_irc::{
-    login::RefreshingLoginCredentials,
-    message::ServerMessage,
-    transport::tcp::{TCPTransport, TLS},
-    ClientConfig, SecureTCPTransport, TwitchIRCClient,
+    login::RefreshingLoginCredentials, ClientConfig, SecureTCPTransport, TwitchIRCClient,
 };
 
 use crate::{
-    commands::get_command,
-    error::TwitchBotResult,
-    spotify::client::SpotifyClient,
-    twitch_auth::{get_user_access_token_async, TwitchTokenStorage},
+    chat::{ChatMessage, ChatReceiver, ChatSender, TwitchChat, TwitchReceiver},
+    commands::{self, get_command, ResponseMode},
+    config::Config,
+    error::{TwitchBotError, TwitchBotResult},
+    helix::HelixClient,
+    history::{RequestOutcome, SongHistory, SongRequest},
+    moderation::{self, ModerationFilters},
+    music::{
+        mpd::MpdClient,
+        playlist::{self, PlaylistMode, RequestsPlaylist, RequestsPlaylistMode},
+        youtube::{self, YoutubePlayer},
+        MusicBackend, MusicProvider, SharedMusicProvider, Track,
+    },
+    outgoing::{ModChannels, OutgoingQueue},
+    request_endpoints::AuthCodes,
+    shoutout::Shoutouts,
+    spotify::client::{SpotifyAuthFlow, SpotifyClient},
+    timers::{self, ChatActivity},
+    token_store::TokenStore,
+    twitch_auth::{self, TwitchTokenStorage},
 };
 
-pub async fn run_async(
-    client_id: String,
-    client_secret: String,
-    spotify_id: String,
-    spotify_secret: String,
-    port: u16,
-    twitch_auth_token: Arc<Mutex<String>>,
-    spotify_auth_token: Arc<Mutex<String>>,
-) -> TwitchBotResult<()> {
-    let twitch_token_exists = tokio::fs::metadata("twitch_token.json").await.is_ok();
-    let spotify_token_exists = tokio::fs::metadata("spotify_token.json").await.is_ok();
+#[derive(Clone)]
+pub struct BotState {
+    pub music_provider: SharedMusicProvider,
+    /// Handles YouTube links in song requests when an mpv socket is configured
+    pub youtube_player: Option<SharedMusicProvider>,
+    pub playlist_mode: Arc<Mutex<PlaylistMode>>,
+    pub requests_playlist: Option<Arc<Mutex<RequestsPlaylist>>>,
+    pub history: Arc<Mutex<SongHistory>>,
+    pub chat_activity: ChatActivity,
+    /// Chat filters, when features.moderation is on
+    pub moderation: Option<Arc<Mutex<ModerationFilters>>>,
+    /// Twitch API for the stream info commands, None when running offline
+    pub helix: Option<HelixClient>,
+    /// Mods can change the title and category, when features.manage_stream_info is on
+    pub manage_stream_info: bool,
+    pub shoutouts: Arc<Shoutouts>,
+}
+
+impl BotState {
+    /// Wires the players together with the playlists and the history enabled in the config
+    pub fn new(
+        config: &Config,
+        music_provider: Box<dyn MusicProvider>,
+        youtube_player: Option<Box<dyn MusicProvider>>,
+        history: SongHistory,
+        helix: Option<HelixClient>,
+    ) -> Self {
+        let playlist_mode = if config.features.fallback_playlists {
+            PlaylistMode::new(config.playlists(), config.spotify.default_playlist.clone())
+        } else {
+            PlaylistMode::default()
+        };
+
+        let requests_playlist = config
+            .features
+            .requests_playlist
+            .then(|| RequestsPlaylist::new(config.spotify.requests_playlist_mode));
 
-    if !twitch_token_exists {
-        while twitch_auth_token.lock().await.as_str() == "" {
-            tracing::info!("Waiting for Twitch auth token");
-            sleep(Duration::from_millis(1000)).await;
+        Self {
+            music_provider: Arc::new(Mutex::new(music_provider)),
+            youtube_player: youtube_player.map(|player| Arc::new(Mutex::new(player))),
+            playlist_mode: Arc::new(Mutex::new(playlist_mode)),
+            requests_playlist: requests_playlist.map(|playlist| Arc::new(Mutex::new(playlist))),
+            history: Arc::new(Mutex::new(history)),
+            chat_activity: ChatActivity::default(),
+            moderation: config
+                .features
+                .moderation
+                .then(|| Arc::new(Mutex::new(ModerationFilters::new(&config.moderation)))),
+            helix,
+            manage_stream_info: config.features.manage_stream_info,
+            shoutouts: Arc::new(Shoutouts::new(config.features.native_shoutouts)),
         }
     }
+}
 
-    if !spotify_token_exists {
-        while spotify_auth_token.lock().await.as_str() == "" {
-            tracing::info!("Waiting for Spotify auth token");
-            sleep(Duration::from_millis(1000)).await;
+pub async fn run_async(
+    config: Config,
+    spotify_auth_flow: SpotifyAuthFlow,
+    auth_codes: AuthCodes,
+    token_store: TokenStore,
+) -> TwitchBotResult<()> {
+    twitch_auth::login_async(&config, auth_codes.twitch, &token_store, false).await?;
+
+    let music_provider: Box<dyn MusicProvider> = match config.music.backend {
+        MusicBackend::Spotify => Box::new(
+            SpotifyClient::login_async(
+                &config,
+                spotify_auth_flow,
+                auth_codes.spotify,
+                token_store.clone(),
+                false,
+            )
+            .await?,
+        ),
+        MusicBackend::Mpd => {
+            let password = config
+                .music
+                .mpd_password
+                .as_ref()
+                .map(|password| password.expose().to_string());
+            Box::new(MpdClient::connect_async(config.music.mpd_address.clone(), password).await?)
         }
-    }
+    };
 
-    let spotify_auth_token_value = spotify_auth_token.lock().await.clone();
-    let mut spotify_client =
-        SpotifyClient::create_async(spotify_id, spotify_secret, spotify_auth_token_value, port)
-            .await?;
+    let youtube_player: Option<Box<dyn MusicProvider>> = match &config.music.mpv_socket {
+        Some(mpv_socket) if config.features.youtube_requests => Some(Box::new(
+            YoutubePlayer::connect_async(mpv_socket.clone()).await?,
+        )),
+        _ => None,
+    };
 
-    let twitch_auth_token_value = twitch_auth_token.lock().await.clone();
-    let token = get_user_access_token_async(
-        client_id.clone(),
-        client_secret.clone(),
-        twitch_auth_token_value,
-        port,
-    )
-    .await?;
+    //TwitchTokenStorage reads the token saved by the login flows back from disk
+    let storage = TwitchTokenStorage { token_store };
 
-    let storage = TwitchTokenStorage { token };
+    let credentials = RefreshingLoginCredentials::init(
+        config.twitch.client_id.clone(),
+        config.twitch_client_secret(),
+        storage,
+    );
 
-    let credentials = RefreshingLoginCredentials::init(client_id, client_secret, storage);
+    //Helix calls share the token twitch-irc refreshes
+    let helix = HelixClient::new(
+        config.twitch.client_id.clone(),
+        credentials.clone(),
+        config.twitch.helix_base_url.clone(),
+    );
+
+    let history = SongHistory::open(&config.storage.history_db)?;
+    let state = BotState::new(
+        &config,
+        music_provider,
+        youtube_player,
+        history,
+        Some(helix.clone()),
+    );
+
+    if config.features.fallback_playlists {
+        tokio::spawn(playlist::run_fallback_async(
+            Arc::clone(&state.music_provider),
+            Arc::clone(&state.playlist_mode),
+        ));
+    }
 
     let twitch_config = ClientConfig::new_simple(credentials);
-    let (mut incoming_messages, client) = TwitchIRCClient::<
+    let (incoming_messages, client) = TwitchIRCClient::<
         SecureTCPTransport,
         RefreshingLoginCredentials<TwitchTokenStorage>,
     >::new(twitch_config);
 
-    client.join("vynny_".to_owned())?;
+    for channel in &config.twitch.channels {
+        client.join(channel.to_lowercase())?;
+    }
 
-    while let Some(message) = incoming_messages.recv().await {
-        process_message(&client, &mut spotify_client, message).await;
+    //Every chat message goes through the queue, so the bot stays within Twitch's rate limits
+    let mod_channels = ModChannels::default();
+    let chat = OutgoingQueue::start(TwitchChat::new(client, helix.clone()), mod_channels.clone());
+
+    if !commands::list_timers().is_empty() {
+        tokio::spawn(timers::run_timers_async(
+            chat.clone(),
+            helix,
+            config
+                .twitch
+                .channels
+                .iter()
+                .map(|channel| channel.to_lowercase())
+                .collect(),
+            state.chat_activity.clone(),
+        ));
     }
 
+    let mut incoming_messages = TwitchReceiver::new(incoming_messages, mod_channels);
+
+    handle_messages_async(&chat, &mut incoming_messages, &state).await;
+
     Ok(())
 }
 
-async fn process_message(
-    client: &TwitchIRCClient<TCPTransport<TLS>, RefreshingLoginCredentials<TwitchTokenStorage>>,
-    spotify_client: &mut SpotifyClient,
-    message: ServerMessage,
+/// Answers every incoming message until the connection closes
+pub async fn handle_messages_async(
+    chat: &impl ChatSender,
+    incoming_messages: &mut impl ChatReceiver,
+    state: &BotState,
 ) {
-    match message {
-        ServerMessage::Privmsg(msg) => {
-            let user = msg.sender.name;
-            let msg_text = msg.message_text;
+    while let Some(message) = incoming_messages.recv().await {
+        process_message(chat, state, message).await;
+    }
+}
 
-            tracing::info!("{}: {}", user, msg_text);
+pub async fn process_message(chat: &impl ChatSender, state: &BotState, msg: ChatMessage) {
+    tracing::info!("{}: {}", msg.sender_name, msg.text);
 
-            if let Some(response) = parse_command(msg_text, spotify_client).await {
-                let _ = client.privmsg("vynny_".to_string(), response).await;
-            }
+    state.chat_activity.record(&msg.channel);
+
+    //Filtered messages never reach the commands
+    if let Some(moderation) = &state.moderation {
+        let verdict = moderation.lock().await.check(&msg);
+
+        if let Some(verdict) = verdict {
+            moderation::apply_verdict_async(chat, &msg, verdict).await;
+            return;
         }
-        _ => (),
+    }
+
+    let Some(response) = parse_command(&msg, state).await else {
+        return;
+    };
+
+    let result = match response.mode {
+        ResponseMode::Reply => chat.reply(&msg, response.text).await,
+        ResponseMode::Message => chat.say(msg.channel, response.text).await,
+        ResponseMode::Me => chat.me(msg.channel, response.text).await,
+    };
+
+    if let Err(e) = result {
+        tracing::warn!("{}", e);
     }
 }
 
-async fn parse_command(msg: String, spotify_client: &mut SpotifyClient) -> Option<String> {
-    let command_message = msg.split_whitespace().nth(0)?.to_lowercase();
-    let arguments_string: String = msg.split_whitespace().skip(1).collect();
+/// What a command answers and how it's sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandResponse {
+    pub text: String,
+    pub mode: ResponseMode,
+}
+
+impl CommandResponse {
+    fn new(text: String, mode: ResponseMode) -> Self {
+        Self { text, mode }
+    }
+}
+
+pub async fn parse_command(msg: &ChatMessage, state: &BotState) -> Option<CommandResponse> {
+    let user = msg.sender_name.as_str();
+    let is_mod = msg.is_mod();
+
+    let command_message = msg.text.split_whitespace().next()?.to_lowercase();
+    let arguments_string = msg
+        .text
+        .split_whitespace()
+        .skip(1)
+        .collect::<Vec<&str>>()
+        .join(" ");
 
     if command_message.starts_with("!") {
-        if let Some(command) =
-            get_command(command_message.to_string(), !arguments_string.is_empty())
-        {
+        if let Some(command) = get_command(
+            command_message.to_string(),
+            !arguments_string.is_empty(),
+            is_mod,
+        ) {
             let response = command.response;
+            let mode = command.response_mode;
 
             if let Some(api_call) = command.api_call {
-                if arguments_string.is_empty() {
-                    return Some(command.usage);
+                if command.requires_arguments && arguments_string.is_empty() {
+                    return Some(CommandResponse::new(command.usage, mode));
                 }
 
                 let api_response = match api_call.as_str() {
                     "play_track" => {
-                        let track = spotify_client
-                            .search_async(arguments_string.as_str())
+                        let is_youtube_link = youtube::video_id(&arguments_string).is_some();
+
+                        let provider = match &state.youtube_player {
+                            Some(youtube_player) if is_youtube_link => youtube_player,
+                            None if is_youtube_link => {
+                                return Some(CommandResponse::new(
+                                    "Pedidos do YouTube estao desativados".to_string(),
+                                    mode,
+                                ))
+                            }
+                            _ => &state.music_provider,
+                        };
+
+                        let mut music_provider = provider.lock().await;
+                        let track = music_provider
+                            .search(arguments_string.as_str())
                             .await
-                            .unwrap_or_default()
-                            .items
-                            .get(0)?
-                            .clone();
+                            .unwrap_or_default();
+
+                        let Some(track) = track else {
+                            record_request(state, SongRequest::from_query(user, &arguments_string))
+                                .await;
+                            return Some(CommandResponse::new(
+                                "Musica nao encontrada".to_string(),
+                                mode,
+                            ));
+                        };
+
+                        let outcome = match music_provider.queue(&track).await {
+                            Ok(()) => RequestOutcome::Queued,
+                            Err(e) => {
+                                tracing::warn!("Could not queue {}: {}", track, e);
+                                RequestOutcome::Rejected
+                            }
+                        };
+
+                        if outcome == RequestOutcome::Queued {
+                            save_request(state, &msg.channel, music_provider.as_mut(), &track)
+                                .await;
+                        }
+
+                        record_request(state, SongRequest::from_track(user, &track, outcome)).await;
+
+                        if outcome == RequestOutcome::Rejected {
+                            return Some(CommandResponse::new(
+                                format!("Nao foi possivel adicionar {} a fila", track),
+                                mode,
+                            ));
+                        }
+
+                        response.replace("<song>", track.to_string().as_str())
+                    }
+                    "now_playing" => {
+                        let (_, track) = now_playing(state).await?;
 
-                        let _ = spotify_client.queue_track(&track).await;
                         response.replace("<song>", track.to_string().as_str())
                     }
+                    "skip_track" => {
+                        let (provider, track) = now_playing(state).await?;
+
+                        if let Err(e) = provider.lock().await.skip().await {
+                            tracing::warn!("Could not skip {}: {}", track, e);
+                            return None;
+                        }
+
+                        if let Err(e) = state.history.lock().await.mark_skipped(&track.id) {
+                            tracing::warn!("Could not mark {} as skipped: {}", track, e);
+                        }
+
+                        response.replace("<song>", track.to_string().as_str())
+                    }
+                    "set_timer" => {
+                        let mut arguments = arguments_string.split_whitespace();
+
+                        let enabled = match arguments.next().map(|state| state.to_lowercase()) {
+                            Some(state) if state == "on" => true,
+                            Some(state) if state == "off" => false,
+                            _ => return Some(CommandResponse::new(command.usage, mode)),
+                        };
+                        let Some(name) = arguments.next() else {
+                            return Some(CommandResponse::new(command.usage, mode));
+                        };
+
+                        if commands::set_timer_enabled(name, enabled) {
+                            response
+                                .replace("<timer>", &name.to_lowercase())
+                                .replace("<state>", if enabled { "ligado" } else { "desligado" })
+                        } else {
+                            let names: Vec<String> = commands::list_timers()
+                                .into_iter()
+                                .map(|(name, _)| name)
+                                .collect();
+                            format!("Timer desconhecido. Disponiveis: {}", names.join(", "))
+                        }
+                    }
+                    "permit_link" => {
+                        let user = arguments_string.trim_start_matches('@').to_lowercase();
+
+                        let permit = match &state.moderation {
+                            Some(moderation) => moderation.lock().await.permit(&msg.channel, &user),
+                            None => None,
+                        };
+
+                        match permit {
+                            Some(duration) => response
+                                .replace("<user>", &user)
+                                .replace("<seconds>", &duration.as_secs().to_string()),
+                            None => "O filtro de links esta desativado".to_string(),
+                        }
+                    }
+                    "uptime" | "game" | "title" | "set_title" | "set_game" => {
+                        stream_info_response(
+                            api_call.as_str(),
+                            response,
+                            &arguments_string,
+                            &msg.channel,
+                            state,
+                        )
+                        .await?
+                    }
+                    "shoutout" => {
+                        let Some(helix) = &state.helix else {
+                            return Some(CommandResponse::new(
+                                "Informacoes da live indisponiveis".to_string(),
+                                mode,
+                            ));
+                        };
+
+                        let shoutout = state
+                            .shoutouts
+                            .shoutout_async(helix, &msg.channel, &arguments_string, &response)
+                            .await;
+
+                        match shoutout {
+                            Ok(Some(shoutout)) => shoutout,
+                            Ok(None) => format!("Usuario {} nao encontrado", arguments_string),
+                            Err(e) => {
+                                tracing::warn!("Could not shout out {}: {}", arguments_string, e);
+                                return None;
+                            }
+                        }
+                    }
+                    "set_playlist" => {
+                        let mut playlist_mode = state.playlist_mode.lock().await;
+                        if playlist_mode.set_active(&arguments_string) {
+                            response.replace("<playlist>", &arguments_string.to_lowercase())
+                        } else {
+                            format!(
+                                "Playlist desconhecida. Disponiveis: {}",
+                                playlist_mode.names().join(", ")
+                            )
+                        }
+                    }
+                    "last_songs" => {
+                        let songs = state.history.lock().await.last_requests(5);
+                        format_songs(response, songs.map(|songs| join_display(&songs)))
+                    }
+                    "my_songs" => {
+                        let songs = state.history.lock().await.user_requests(user, 5);
+                        format_songs(response, songs.map(|songs| join_display(&songs)))
+                    }
+                    "top_songs" => {
+                        let songs = state.history.lock().await.top_requests(5);
+                        format_songs(response, songs.map(|songs| join_display(&songs)))
+                    }
                     _ => "".to_string(),
                 };
 
-                return Some(api_response);
+                return Some(CommandResponse::new(api_response, mode));
             }
 
-            return Some(response);
+            return Some(CommandResponse::new(response, mode));
         }
     }
 
     None
 }
+
+/// Commands reading or changing the stream's title and category through Helix
+async fn stream_info_response(
+    api_call: &str,
+    response: String,
+    arguments: &str,
+    channel: &str,
+    state: &BotState,
+) -> Option<String> {
+    let Some(helix) = &state.helix else {
+        return Some("Informacoes da live indisponiveis".to_string());
+    };
+
+    if matches!(api_call, "set_title" | "set_game") && !state.manage_stream_info {
+        return Some("Alterar titulo e categoria esta desativado".to_string());
+    }
+
+    let result = match api_call {
+        "uptime" => helix
+            .get_stream_async(channel)
+            .await
+            .map(|stream| match stream {
+                Some(stream) => {
+                    response.replace("<uptime>", &format_uptime(stream.started_at, Utc::now()))
+                }
+                None => "O canal esta offline".to_string(),
+            }),
+        "game" | "title" => helix
+            .get_channel_async(channel)
+            .await
+            .map(|info| match info {
+                Some(info) => response
+                    .replace("<game>", &info.game_name)
+                    .replace("<title>", &info.title),
+                None => "Canal nao encontrado".to_string(),
+            }),
+        "set_title" => helix
+            .update_channel_async(channel, Some(arguments), None)
+            .await
+            .map(|_| response.replace("<title>", arguments)),
+        "set_game" => match helix.search_categories_async(arguments).await {
+            Ok(categories) => {
+                //Prefer the exact name, the search also returns partial matches
+                let category = categories
+                    .iter()
+                    .find(|category| category.name.eq_ignore_ascii_case(arguments))
+                    .or(categories.first());
+
+                match category {
+                    Some(category) => helix
+                        .update_channel_async(channel, None, Some(&category.id))
+                        .await
+                        .map(|_| response.replace("<game>", &category.name)),
+                    None => Ok(format!("Nenhuma categoria encontrada para {}", arguments)),
+                }
+            }
+            Err(e) => Err(e),
+        },
+        _ => Ok("".to_string()),
+    };
+
+    match result {
+        Ok(response) => Some(response),
+        Err(e) => {
+            tracing::warn!(
+                "Could not {} in #{}: {}",
+                api_call.replace('_', " "),
+                channel,
+                e
+            );
+
+            match api_call {
+                "set_title" | "set_game" => Some(
+                    "Nao consegui alterar, o bot precisa estar logado na conta do canal"
+                        .to_string(),
+                ),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// "2h 05m", or just the minutes in the first hour
+fn format_uptime(started_at: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let minutes = (now - started_at).num_minutes().max(0);
+
+    match minutes / 60 {
+        0 => format!("{}m", minutes),
+        hours => format!("{}h {:02}m", hours, minutes % 60),
+    }
+}
+
+/// The player that is currently playing something, YouTube requests take priority
+async fn now_playing(state: &BotState) -> Option<(&SharedMusicProvider, Track)> {
+    let providers = state.youtube_player.iter().chain([&state.music_provider]);
+
+    for provider in providers {
+        if let Ok(Some(track)) = provider.lock().await.now_playing().await {
+            return Some((provider, track));
+        }
+    }
+
+    None
+}
+
+/// Adds a queued request to the requests playlist, if the feature is on
+async fn save_request(
+    state: &BotState,
+    channel: &str,
+    music_provider: &mut dyn MusicProvider,
+    track: &Track,
+) {
+    let Some(requests_playlist) = &state.requests_playlist else {
+        return;
+    };
+    let requests_playlist = requests_playlist.lock().await;
+
+    let stream_started_at = match (&state.helix, requests_playlist.mode()) {
+        (Some(helix), RequestsPlaylistMode::PerStream) => {
+            match helix.get_stream_async(channel).await {
+                Ok(stream) => stream.map(|stream| stream.started_at),
+                Err(e) => {
+                    tracing::warn!("Could not look up the stream of #{}: {}", channel, e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    match requests_playlist
+        .save_track(music_provider, stream_started_at, track)
+        .await
+    {
+        Ok(()) | Err(TwitchBotError::UnsupportedByMusicBackend(_)) => {}
+        Err(e) => tracing::warn!(
+            "Could not save {} to {}: {}",
+            track,
+            requests_playlist.name(stream_started_at),
+            e
+        ),
+    }
+}
+
+async fn record_request(state: &BotState, request: SongRequest) {
+    if let Err(e) = state.history.lock().await.record(&request) {
+        tracing::warn!("Could not save song request to history: {}", e);
+    }
+}
+
+fn format_songs(response: String, songs: TwitchBotResult<String>) -> String {
+    match songs {
+        Ok(songs) if !songs.is_empty() => response.replace("<songs>", &songs),
+        Ok(_) => "Nenhuma musica pedida ainda".to_string(),
+        Err(e) => {
+            tracing::warn!("Could not read song history: {}", e);
+            "".to_string()
+        }
+    }
+}
+
+fn join_display<T: std::fmt::Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<String>>()
+        .join(" | ")
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        chat::{ChatAction, MemoryChat},
+        music::stub::StubMusicProvider,
+    };
+
+    const CHANNEL: &str = "canal";
+
+    fn state() -> BotState {
+        BotState::new(
+            &Config::default(),
+            Box::new(StubMusicProvider::new()),
+            None,
+            SongHistory::open(":memory:").unwrap(),
+            None,
+        )
+    }
+
+    fn viewer(text: &str) -> ChatMessage {
+        ChatMessage::local(CHANNEL, "Viewer", &[], text)
+    }
+
+    fn moderator(text: &str) -> ChatMessage {
+        ChatMessage::local(CHANNEL, "Moderador", &["moderator".to_string()], text)
+    }
+
+    //Goes through the same loop as a live channel, returning everything the bot sent
+    async fn run(state: &BotState, messages: Vec<ChatMessage>) -> Vec<ChatAction> {
+        let chat = MemoryChat::new();
+        let (sender, mut incoming_messages) = mpsc::unbounded_channel();
+        for message in messages {
+            sender.send(message).unwrap();
+        }
+        drop(sender);
+
+        handle_messages_async(&chat, &mut incoming_messages, state).await;
+
+        chat.take_sent()
+    }
+
+    fn reply(parent: &ChatMessage, message: &str) -> ChatAction {
+        ChatAction::Reply {
+            channel: CHANNEL.to_string(),
+            parent_id: parent.message_id.clone(),
+            message: message.to_string(),
+        }
+    }
+
+    //Commands are global, each test loads its own so the cooldowns don't collide
+    fn load_commands(contents: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("commands.toml");
+        std::fs::write(&path, contents).unwrap();
+
+        commands::load_commands_file(&path).unwrap();
+    }
+
+    #[test]
+    fn uptime_shows_hours_only_after_the_first_hour() {
+        let started_at = DateTime::UNIX_EPOCH;
+        let uptime = |seconds| {
+            format_uptime(
+                started_at,
+                started_at + chrono::Duration::try_seconds(seconds).unwrap(),
+            )
+        };
+
+        assert_eq!(uptime(0), "0m");
+        assert_eq!(uptime(59 * 60 + 59), "59m");
+        assert_eq!(uptime(60 * 60), "1h 00m");
+        assert_eq!(uptime(2 * 60 * 60 + 5 * 60), "2h 05m");
+        assert_eq!(uptime(26 * 60 * 60 + 30 * 60), "26h 30m");
+        //A start time slightly in the future, from clock drift
+        assert_eq!(uptime(-30), "0m");
+    }
+
+    #[tokio::test]
+    async fn song_request_is_queued_and_recorded() {
+        let state = state();
+        let request = viewer("!sr Musica - Artista");
+
+        let sent = run(&state, vec![request.clone()]).await;
+
+        assert_eq!(
+            sent,
+            vec![reply(&request, "Musica Musica - Artista adicionada a fila")]
+        );
+
+        let playing = state
+            .music_provider
+            .lock()
+            .await
+            .now_playing()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(playing.to_string(), "Musica - Artista");
+
+        let requests = state.history.lock().await.last_requests(5).unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].user, "viewer");
+        assert_eq!(requests[0].outcome, RequestOutcome::Queued);
+    }
+
+    #[tokio::test]
+    async fn mod_only_command_ignores_viewers() {
+        let state = state();
+        let track = Track {
+            id: "stub:1".to_string(),
+            name: "Musica".to_string(),
+            artist: None,
+            duration_ms: 0,
+        };
+        state
+            .music_provider
+            .lock()
+            .await
+            .queue(&track)
+            .await
+            .unwrap();
+
+        assert!(run(&state, vec![viewer("!skip")]).await.is_empty());
+
+        let skip = moderator("!skip");
+        let sent = run(&state, vec![skip.clone()]).await;
+
+        assert_eq!(sent, vec![reply(&skip, "Musica Musica pulada")]);
+    }
+
+    #[tokio::test]
+    async fn command_is_ignored_during_its_cooldown() {
+        load_commands(
+            r#"
+            [[commands]]
+            name = "!pipelinecooldown"
+            response = "Resposta"
+            timeout_seconds = 60
+            "#,
+        );
+        let state = state();
+        let first = viewer("!pipelinecooldown");
+
+        let sent = run(&state, vec![first.clone(), viewer("!pipelinecooldown")]).await;
+
+        assert_eq!(sent, vec![reply(&first, "Resposta")]);
+        assert!(run(&state, vec![moderator("!pipelinecooldown")])
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn commands_answer_in_their_response_mode() {
+        load_commands(
+            r#"
+            [[commands]]
+            name = "!pipelinereply"
+            response = "Resposta"
+
+            [[commands]]
+            name = "!pipelinemessage"
+            response = "Mensagem"
+            response_mode = "message"
+
+            [[commands]]
+            name = "!pipelineme"
+            response = "Acao"
+            response_mode = "me"
+            "#,
+        );
+        let state = state();
+        let reply_message = viewer("!pipelinereply");
+
+        let sent = run(
+            &state,
+            vec![
+                reply_message.clone(),
+                viewer("!PipelineMessage"),
+                viewer("!pipelineme com argumentos"),
+                viewer("nao e um comando"),
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            sent,
+            vec![
+                reply(&reply_message, "Resposta"),
+                ChatAction::Say {
+                    channel: CHANNEL.to_string(),
+                    message: "Mensagem".to_string(),
+                },
+                ChatAction::Me {
+                    channel: CHANNEL.to_string(),
+                    message: "Acao".to_string(),
+                },
+            ]
+        );
+    }
+}